@@ -7,6 +7,12 @@ pub struct Logger {
     stderr: Stderr,
 }
 
+impl Default for Logger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Logger {
     /// Creates a new logger which will write logs into the given `log_file`.
     pub fn new() -> Self {
@@ -19,11 +25,11 @@ impl Logger {
     /// a newline.
     pub fn log<S: AsRef<str>>(&mut self, string: S) {
         let log_time = unix_time();
-        write!(&mut self.stdout, "{},{}\n", log_time, string.as_ref())
+        writeln!(&mut self.stdout, "{},{}", log_time, string.as_ref())
             .expect("Cannot write to stdout");
     }
 
     pub fn log_msg<S: AsRef<str>>(&mut self, string: S) {
-        write!(&mut self.stderr, "{}\n", string.as_ref()).expect("Cannot write to stderr");
+        writeln!(&mut self.stderr, "{}", string.as_ref()).expect("Cannot write to stderr");
     }
 }
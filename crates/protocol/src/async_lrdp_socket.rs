@@ -0,0 +1,719 @@
+use crate::client_state::ConnectionState;
+use crate::datagram_transport;
+use crate::lrdp_config::LrdpConfig;
+use crate::lrdp_delivery::{DeliveryReport, MessageId, SendOptions};
+use crate::lrdp_error::{LrdpError, LrdpResult};
+use crate::lrdp_stats::LrdpStats;
+use crate::rtt_estimator::RttEstimator;
+use crate::socket_core::{self, AddressedBuffer, Output, SocketCore};
+use crate::timer_queue::TimerQueue;
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+use tokio::net::{lookup_host, ToSocketAddrs, UdpSocket};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender, WeakUnboundedSender};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+/// A request for the driver task to call `handle_timeout` for a client at the given time.
+type Timer = (SocketAddr, Instant);
+
+/// The channels which deliveries are reported on.
+type ReceiptSenders = Arc<Mutex<Vec<UnboundedSender<DeliveryReport>>>>;
+
+/// Resolves `addr` to the first socket address it refers to.
+async fn resolve<A: ToSocketAddrs>(addr: A) -> io::Result<SocketAddr> {
+    lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No address to send to"))
+}
+
+/// The UDP socket, along with the buffer which every datagram sent over it is written to.
+struct Transport {
+    socket: UdpSocket,
+    send_buf: tokio::sync::Mutex<Box<[u8]>>,
+}
+
+/// Sends every datagram which the `core` has to send over the `transport`, then acts on the rest of
+/// the output by emitting data on `data_tx`, reporting deliveries on `receipt_txs`, notifying
+/// `state_changed` and scheduling timers on `timer_tx`. The core is only locked while each datagram
+/// is written, never while it is sent. If sending fails, the rest of the output is still acted on
+/// and the error is returned.
+async fn flush(
+    transport: &Transport,
+    core: &Mutex<SocketCore>,
+    mut out: Output,
+    data_tx: &UnboundedSender<AddressedBuffer>,
+    receipt_txs: &ReceiptSenders,
+    state_changed: &Notify,
+    timer_tx: &UnboundedSender<Timer>,
+) -> io::Result<()> {
+    let mut sent = Ok(());
+    let mut send_buf = transport.send_buf.lock().await;
+    let now = Instant::now();
+    loop {
+        let polled = match core.lock() {
+            Ok(mut core) => core.poll_transmit(now, &mut send_buf, &mut out),
+            Err(_) => None,
+        };
+        let (len, addr) = match polled {
+            Some(polled) => polled,
+            None => break,
+        };
+        if let Err(e) = transport.socket.send_to(&send_buf[..len], addr).await {
+            sent = Err(e);
+            break;
+        }
+    }
+    drop(send_buf);
+    for emitted in out.emitted {
+        // emit data. Don't really care about the result.
+        let _ = data_tx.send(emitted);
+    }
+    let receipts = out.receipts;
+    if !receipts.is_empty() {
+        if let Ok(mut receipt_txs) = receipt_txs.lock() {
+            // subscribers which have gone away are forgotten about.
+            receipt_txs.retain(|receipt_tx| {
+                receipts
+                    .iter()
+                    .all(|receipt| receipt_tx.send(*receipt).is_ok())
+            });
+        }
+    }
+    for timer in out.timers {
+        let _ = timer_tx.send(timer);
+    }
+    if out.state_changed {
+        state_changed.notify_waiters();
+    }
+    sent
+}
+
+/// Drives the socket by handing received datagrams and expired timers to the core. This runs as a
+/// single task on the runtime, and sleeps until the next datagram or the earliest deadline of any
+/// client. The driver only holds a weak sender for its own timers, so it finishes once the socket
+/// has gone away.
+async fn drive(
+    transport: Arc<Transport>,
+    core: Arc<Mutex<SocketCore>>,
+    data_tx: UnboundedSender<AddressedBuffer>,
+    receipt_txs: ReceiptSenders,
+    state_changed: Arc<Notify>,
+    timer_tx: WeakUnboundedSender<Timer>,
+    mut timer_rx: UnboundedReceiver<Timer>,
+) {
+    let this_addr = transport
+        .socket
+        .local_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    let mut buf = vec![0u8; u16::MAX as usize];
+    let mut timers = TimerQueue::new();
+    loop {
+        let deadline = timers.next_deadline();
+        let sleep = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now).into());
+        let mut out = Output::default();
+        tokio::select! {
+            result = transport.socket.recv_from(&mut buf) => match result {
+                Ok((recv, addr)) => {
+                    log::debug!(
+                        target: &this_addr,
+                        "Received UDP packet from {}",
+                        addr.to_string()
+                    );
+                    let mut core = match core.lock() {
+                        Ok(core) => core,
+                        Err(_) => break,
+                    };
+                    core.handle_datagram(&buf[0..recv], addr, Instant::now(), &mut out);
+                }
+                Err(e) if datagram_transport::is_transient(&e) => {
+                    log::warn!(target: &this_addr, "Error receiving UDP packet: {}", e);
+                    continue;
+                }
+                Err(e) => {
+                    log::error!(target: &this_addr, "UDP socket failed: {}", e);
+                    break;
+                }
+            },
+            timer = timer_rx.recv() => match timer {
+                Some((addr, deadline)) => {
+                    timers.schedule(addr, deadline);
+                    continue;
+                }
+                None => break,
+            },
+            _ = sleep, if deadline.is_some() => {
+                let now = Instant::now();
+                let mut core = match core.lock() {
+                    Ok(core) => core,
+                    Err(_) => break,
+                };
+                while let Some(addr) = timers.pop_expired(now) {
+                    core.handle_timeout(addr, now);
+                }
+            }
+        }
+        let timer_tx = match timer_tx.upgrade() {
+            Some(timer_tx) => timer_tx,
+            None => break,
+        };
+        let flushed = flush(
+            &transport,
+            &core,
+            out,
+            &data_tx,
+            &receipt_txs,
+            &state_changed,
+            &timer_tx,
+        );
+        if let Err(e) = flushed.await {
+            log::warn!(target: &this_addr, "Error sending UDP packet: {}", e);
+        }
+    }
+    log::trace!(target: &this_addr, "driver task at end.");
+}
+
+/// An LRDP socket for use with tokio. This behaves the same as `LrdpSocket`, but instead of running
+/// its own threads it spawns a single task on the runtime, and its timers are driven by the
+/// runtime.
+pub struct AsyncLrdpSocket {
+    transport: Arc<Transport>,
+    core: Arc<Mutex<SocketCore>>,
+    /// Notified whenever the connection state of a client changes, or space is freed in its send
+    /// queue.
+    state_changed: Arc<Notify>,
+    timer_tx: UnboundedSender<Timer>,
+    data_tx: UnboundedSender<AddressedBuffer>,
+    data_rx: UnboundedReceiver<AddressedBuffer>,
+    /// Subscribers which are told about every message which is acknowledged or given up on.
+    receipt_txs: ReceiptSenders,
+    driver: JoinHandle<()>,
+    config: LrdpConfig,
+}
+
+impl AsyncLrdpSocket {
+    /// Creates an LRDP socket bound to `addrs` which uses the default configuration. This must be
+    /// called from within a tokio runtime.
+    pub async fn bind<A: ToSocketAddrs>(addrs: A) -> LrdpResult<Self> {
+        Self::bind_with_config(addrs, LrdpConfig::default()).await
+    }
+
+    /// Creates an LRDP socket bound to `addrs` which uses the given `config`. This must be called
+    /// from within a tokio runtime.
+    pub async fn bind_with_config<A: ToSocketAddrs>(
+        addrs: A,
+        config: LrdpConfig,
+    ) -> LrdpResult<Self> {
+        let socket = UdpSocket::bind(addrs).await?;
+        let core = Arc::new(Mutex::new(SocketCore::new(socket.local_addr()?, config)));
+        let transport = Arc::new(Transport {
+            socket,
+            send_buf: tokio::sync::Mutex::new(vec![0u8; u16::MAX as usize].into_boxed_slice()),
+        });
+        let state_changed = Arc::new(Notify::new());
+        let (data_tx, data_rx) = mpsc::unbounded_channel();
+        let (timer_tx, timer_rx) = mpsc::unbounded_channel();
+        let receipt_txs = ReceiptSenders::default();
+
+        let driver = tokio::spawn(drive(
+            transport.clone(),
+            core.clone(),
+            data_tx.clone(),
+            receipt_txs.clone(),
+            state_changed.clone(),
+            timer_tx.downgrade(),
+            timer_rx,
+        ));
+
+        Ok(Self {
+            transport,
+            core,
+            state_changed,
+            timer_tx,
+            data_tx,
+            data_rx,
+            receipt_txs,
+            driver,
+            config,
+        })
+    }
+
+    /// Locks the core. This only fails if the driver task has died while holding it.
+    fn lock(&self) -> LrdpResult<MutexGuard<'_, SocketCore>> {
+        self.core.lock().map_err(|_| LrdpError::SocketStopped)
+    }
+
+    /// Acts on the output of the core.
+    async fn flush(&self, out: Output) -> io::Result<()> {
+        flush(
+            &self.transport,
+            &self.core,
+            out,
+            &self.data_tx,
+            &self.receipt_txs,
+            &self.state_changed,
+            &self.timer_tx,
+        )
+        .await
+    }
+
+    /// Waits until `condition` no longer holds for the core. This does not time out by itself.
+    async fn wait_while<F: Fn(&SocketCore) -> bool>(&self, condition: F) -> LrdpResult<()> {
+        loop {
+            // the future is created before checking the condition so that no notification can be
+            // missed in between.
+            let notified = self.state_changed.notified();
+            let waiting = condition(&*self.lock()?);
+            if !waiting {
+                return Ok(());
+            }
+            notified.await;
+        }
+    }
+
+    /// Opens a connection with the peer at `addr`, and waits until the peer has acknowledged it.
+    /// If there is already a connection with the peer, this returns straight away.
+    pub async fn open_to<A: ToSocketAddrs>(&mut self, addr: A) -> LrdpResult<()> {
+        let address = resolve(addr).await?;
+        let opened = self.lock()?.open(address);
+        opened?;
+        self.flush(Output::default()).await?;
+
+        // wait for the SYN-ACK.
+        if let Ok(waited) = tokio::time::timeout(
+            self.config.handshake_timeout,
+            self.wait_while(|core| core.state(address) == Some(ConnectionState::Opening)),
+        )
+        .await
+        {
+            waited?;
+        }
+        let mut core = self.lock()?;
+        match core.state(address) {
+            Some(ConnectionState::Opening) => {
+                core.remove(address);
+                Err(io::Error::new(io::ErrorKind::TimedOut, "Peer did not acknowledge SYN").into())
+            }
+            Some(ConnectionState::Unreachable) => {
+                core.remove(address);
+                Err(LrdpError::PeerUnreachable)
+            }
+            Some(_) => Ok(()),
+            None => Err(LrdpError::PeerClosed),
+        }
+    }
+
+    /// Closes the connection with the peer at `addr`, and waits until the peer has acknowledged
+    /// it. Any data which is queued for the peer is delivered before the connection is closed. If
+    /// there is no connection with the peer, this returns straight away.
+    pub async fn close_to<A: ToSocketAddrs>(&mut self, addr: A) -> LrdpResult<()> {
+        let address = resolve(addr).await?;
+        self.lock()?.close(address);
+        self.flush(Output::default()).await?;
+
+        // wait for the FIN-ACK.
+        if let Ok(waited) = tokio::time::timeout(
+            self.config.handshake_timeout,
+            self.wait_while(|core| {
+                core.state(address)
+                    .is_some_and(|state| state != ConnectionState::Unreachable)
+            }),
+        )
+        .await
+        {
+            waited?;
+        }
+        let removed = self.lock()?.remove(address);
+        match removed {
+            Some(ConnectionState::Unreachable) => Err(LrdpError::PeerUnreachable),
+            Some(_) => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "Peer did not acknowledge FIN").into())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Connects the socket to the peer at `addr`, opening a connection with it first if there is
+    /// not one already. Once connected, `send` and `recv` talk to the peer without naming it, and
+    /// datagrams from any other address are ignored.
+    pub async fn connect<A: ToSocketAddrs>(&mut self, addr: A) -> LrdpResult<()> {
+        let address = resolve(addr).await?;
+        self.open_to(address).await?;
+        self.lock()?.connect(address);
+
+        Ok(())
+    }
+
+    /// Returns the address of the peer which the socket is connected to. If the socket is not
+    /// connected, an I/O error of the `NotConnected` kind is returned.
+    pub fn peer_addr(&self) -> LrdpResult<SocketAddr> {
+        self.lock()?.peer().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "Socket is not connected").into()
+        })
+    }
+
+    /// Sends `data` reliably to the connected peer, like `send_to`.
+    pub async fn send(&mut self, data: &[u8]) -> LrdpResult<MessageId> {
+        let peer = self.peer_addr()?;
+        self.send_to(peer, data).await
+    }
+
+    /// Receives data from the connected peer. When the peer closes the connection or is evicted
+    /// for being idle, `LrdpError::PeerClosed` is returned, and when it is declared unreachable,
+    /// `LrdpError::PeerUnreachable` is returned.
+    pub async fn recv(&mut self) -> LrdpResult<Vec<u8>> {
+        let peer = self.peer_addr()?;
+        loop {
+            let (data, addr) = self.recv_from().await?;
+            // anything from other peers was received before the socket was connected.
+            if addr != peer {
+                continue;
+            }
+            if data.is_empty() {
+                if let Some(e) = self.lock()?.connection_lost(peer) {
+                    return Err(e);
+                }
+            }
+            return Ok(data);
+        }
+    }
+
+    /// Sends `data` reliably to `addr`. If there is no connection with the peer yet, one is opened
+    /// first, and the data is sent once the peer has acknowledged it. If too many packets are
+    /// already waiting to be acknowledged by the peer, this waits until there is space for
+    /// another. If the peer has been declared unreachable since the last call,
+    /// `LrdpError::PeerUnreachable` is returned instead.
+    ///
+    /// The returned id shows up in the reports from `receipts` once the peer has acknowledged the
+    /// data, or once it has been given up on.
+    pub async fn send_to<A: ToSocketAddrs>(
+        &mut self,
+        addr: A,
+        data: &[u8],
+    ) -> LrdpResult<MessageId> {
+        self.send_to_with(addr, data, SendOptions::default()).await
+    }
+
+    /// Sends `data` to `addr` like `send_to`, treating it according to `options`. If the message
+    /// has a time to live, it is given up on once that has passed, and it is reported as expired.
+    pub async fn send_to_with<A: ToSocketAddrs>(
+        &mut self,
+        addr: A,
+        data: &[u8],
+        options: SendOptions,
+    ) -> LrdpResult<MessageId> {
+        let address = resolve(addr).await?;
+        self.wait_while(|core| core.is_full(address)).await?;
+        self.send_now(address, data, options).await
+    }
+
+    /// Sends `data` reliably to `addr` like `send_to`, but never waits for space in the send
+    /// queue. If too many packets are already waiting to be acknowledged by the peer,
+    /// `LrdpError::QueueFull` is returned and the data should be sent again later.
+    pub async fn try_send_to<A: ToSocketAddrs>(
+        &mut self,
+        addr: A,
+        data: &[u8],
+    ) -> LrdpResult<MessageId> {
+        let address = resolve(addr).await?;
+        self.send_now(address, data, SendOptions::default()).await
+    }
+
+    /// Queues `data` for the peer at `addr` and sends it if it is inside the send window.
+    async fn send_now(
+        &self,
+        address: SocketAddr,
+        data: &[u8],
+        options: SendOptions,
+    ) -> LrdpResult<MessageId> {
+        let sent = self.lock()?.send(address, data, options, Instant::now());
+        let id = sent?;
+        self.flush(Output::default()).await?;
+
+        Ok(id)
+    }
+
+    /// Returns a channel which is told about every message that any peer acknowledges from now on,
+    /// along with how long it took, and every message which is given up on. Each call returns a
+    /// new channel, and every channel hears about every message.
+    pub fn receipts(&self) -> UnboundedReceiver<DeliveryReport> {
+        let (receipt_tx, receipt_rx) = mpsc::unbounded_channel();
+        if let Ok(mut receipt_txs) = self.receipt_txs.lock() {
+            receipt_txs.push(receipt_tx);
+        }
+        receipt_rx
+    }
+
+    /// Sends `data` to `addr` without any delivery guarantees. The packet is never queued or
+    /// retransmitted, and the receiver emits it without touching its sequence state.
+    pub async fn send_unreliable_to<A: ToSocketAddrs>(
+        &mut self,
+        addr: A,
+        data: &[u8],
+    ) -> LrdpResult<()> {
+        let mut send_buf = self.transport.send_buf.lock().await;
+        let len = socket_core::encode_unreliable(data, self.config.header_format, &mut send_buf)?;
+        self.transport
+            .socket
+            .send_to(&send_buf[..len], addr)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Returns the round trip time estimate for the peer at `addr`, or `None` if this socket does
+    /// not know about the peer.
+    pub fn peer_rtt(&self, addr: SocketAddr) -> Option<RttEstimator> {
+        self.lock().ok()?.rtt(addr)
+    }
+
+    /// Returns the traffic statistics of the connection with the peer at `addr`, or `None` if
+    /// there is no connection with it.
+    pub fn peer_stats(&self, addr: SocketAddr) -> Option<LrdpStats> {
+        self.lock().ok()?.peer_stats(addr)
+    }
+
+    /// Returns the traffic statistics of every peer which this socket has a connection with.
+    pub fn stats(&self) -> HashMap<SocketAddr, LrdpStats> {
+        self.lock().map(|core| core.stats()).unwrap_or_default()
+    }
+
+    /// Returns the number of datagrams which this socket has dropped because they were not valid
+    /// LRDP packets.
+    pub fn malformed_datagrams(&self) -> u64 {
+        self.lock().map_or(0, |core| core.malformed_datagrams())
+    }
+
+    /// Returns the address that this socket is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.transport.socket.local_addr()
+    }
+
+    /// Receives data from any peer. When a peer closes its connection, is evicted for being idle or
+    /// is declared unreachable, an empty buffer is received from it.
+    pub async fn recv_from(&mut self) -> LrdpResult<AddressedBuffer> {
+        self.data_rx.recv().await.ok_or(LrdpError::SocketStopped)
+    }
+
+    /// Stops the socket once all of the data which is queued for its peers has been acknowledged,
+    /// including the FINs of connections which are being closed, or the configured `stop_timeout`
+    /// has passed. Returns the messages which were never acknowledged, along with the peer they
+    /// were for. The connections are not closed, so peers only find out that the socket has gone
+    /// once they time out.
+    pub async fn stop(mut self) -> Vec<AddressedBuffer> {
+        let this_addr = self
+            .local_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+        log::info!(target: &this_addr, "Stopping socket...");
+
+        let undelivered = match self.drain().await {
+            Ok(undelivered) => undelivered,
+            Err(e) => {
+                log::warn!(target: &this_addr, "Could not flush queued data: {}", e);
+                Vec::new()
+            }
+        };
+        if !undelivered.is_empty() {
+            log::warn!(
+                target: &this_addr,
+                "Giving up on {} undelivered messages.",
+                undelivered.len()
+            );
+        }
+
+        self.driver.abort();
+        let _ = (&mut self.driver).await;
+        log::info!(target: &this_addr, "Socket stopped.");
+        undelivered
+    }
+
+    /// Waits until all of the queued data has been acknowledged or the configured `stop_timeout`
+    /// has passed, and takes whatever is left in the send queues.
+    async fn drain(&self) -> LrdpResult<Vec<AddressedBuffer>> {
+        let drained = tokio::time::timeout(
+            self.config.stop_timeout,
+            self.wait_while(|core| core.has_queued_data()),
+        );
+        if let Ok(waited) = drained.await {
+            waited?;
+        }
+        let undelivered = self.lock()?.take_undelivered();
+        self.flush(Output::default()).await?;
+        Ok(undelivered)
+    }
+}
+
+impl Drop for AsyncLrdpSocket {
+    /// Stops the driver task straight away, without waiting for queued data to be acknowledged.
+    fn drop(&mut self) {
+        self.driver.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lrdp_delivery::Delivery;
+    use std::time::Duration;
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn send_and_close() {
+        runtime().block_on(async {
+            let mut receiver = AsyncLrdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut sender = AsyncLrdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let receiver_addr = receiver.local_addr().unwrap();
+            let sender_addr = sender.local_addr().unwrap();
+            let mut receipts = sender.receipts();
+
+            let mut ids = Vec::new();
+            for i in 0..3 {
+                ids.push(sender.send_to(receiver_addr, &[i]).await.unwrap());
+            }
+            sender.close_to(receiver_addr).await.unwrap();
+
+            // everything was acknowledged before the connection was closed.
+            for id in ids {
+                let (acked, addr, delivery) = receipts.recv().await.unwrap();
+                assert_eq!((acked, addr), (id, receiver_addr));
+                assert!(matches!(delivery, Delivery::Acknowledged(_)));
+            }
+
+            for i in 0..3 {
+                assert_eq!(receiver.recv_from().await.unwrap(), (vec![i], sender_addr));
+            }
+            // the empty buffer means the sender closed the connection.
+            assert_eq!(
+                receiver.recv_from().await.unwrap(),
+                (Vec::new(), sender_addr)
+            );
+            sender.stop().await;
+            receiver.stop().await;
+        });
+    }
+
+    #[test]
+    fn connected() {
+        runtime().block_on(async {
+            let mut server = AsyncLrdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut client = AsyncLrdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut stranger = AsyncLrdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let server_addr = server.local_addr().unwrap();
+            let client_addr = client.local_addr().unwrap();
+            assert!(matches!(
+                client.send(&[1]).await,
+                Err(LrdpError::Io(e)) if e.kind() == io::ErrorKind::NotConnected
+            ));
+
+            client.connect(server_addr).await.unwrap();
+            assert_eq!(client.peer_addr().unwrap(), server_addr);
+            client.send(&[1]).await.unwrap();
+            assert_eq!(server.recv_from().await.unwrap(), (vec![1], client_addr));
+
+            // only the connected peer is heard from.
+            stranger
+                .send_unreliable_to(client_addr, &[3])
+                .await
+                .unwrap();
+            server.send_to(client_addr, &[2]).await.unwrap();
+            assert_eq!(client.recv().await.unwrap(), vec![2]);
+
+            server.close_to(client_addr).await.unwrap();
+            assert!(matches!(client.recv().await, Err(LrdpError::PeerClosed)));
+            server.stop().await;
+            client.stop().await;
+            stranger.stop().await;
+        });
+    }
+
+    #[test]
+    fn queue_full() {
+        runtime().block_on(async {
+            let mut socket = AsyncLrdpSocket::bind("127.0.0.1:0").await.unwrap();
+            // nothing ever acknowledges the data, so the queue fills up.
+            let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            let silent_addr = silent.local_addr().unwrap();
+
+            for i in 0..4 {
+                socket.try_send_to(silent_addr, &[i]).await.unwrap();
+            }
+            assert!(matches!(
+                socket.try_send_to(silent_addr, &[4]).await,
+                Err(LrdpError::QueueFull)
+            ));
+        });
+    }
+
+    #[test]
+    fn stop_returns_undelivered_data() {
+        runtime().block_on(async {
+            let config = LrdpConfig {
+                stop_timeout: Duration::from_millis(100),
+                ..LrdpConfig::default()
+            };
+            let mut socket = AsyncLrdpSocket::bind_with_config("127.0.0.1:0", config)
+                .await
+                .unwrap();
+            // nothing ever answers on this address.
+            let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            let silent_addr = silent.local_addr().unwrap();
+            socket.try_send_to(silent_addr, &[1, 2, 3]).await.unwrap();
+            socket.try_send_to(silent_addr, &[4]).await.unwrap();
+
+            assert_eq!(
+                socket.stop().await,
+                vec![(vec![1, 2, 3], silent_addr), (vec![4], silent_addr)]
+            );
+        });
+    }
+
+    #[test]
+    fn drop_releases_address() {
+        runtime().block_on(async {
+            let socket = AsyncLrdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = socket.local_addr().unwrap();
+            drop(socket);
+
+            // the driver task lets go of the socket once the runtime gets round to it.
+            tokio::task::yield_now().await;
+            std::net::UdpSocket::bind(addr).unwrap();
+        });
+    }
+
+    #[test]
+    fn unreachable_peer() {
+        runtime().block_on(async {
+            let config = LrdpConfig {
+                max_retransmissions: Some(0),
+                ..LrdpConfig::default()
+            };
+            let mut socket = AsyncLrdpSocket::bind_with_config("127.0.0.1:0", config)
+                .await
+                .unwrap();
+            // nothing is listening on this socket, so the SYN is never acknowledged.
+            let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            let silent_addr = silent.local_addr().unwrap();
+
+            socket.send_to(silent_addr, &[1]).await.unwrap();
+            assert_eq!(socket.recv_from().await.unwrap(), (Vec::new(), silent_addr));
+            assert!(matches!(
+                socket.send_to(silent_addr, &[1]).await,
+                Err(LrdpError::PeerUnreachable)
+            ));
+            socket.stop().await;
+        });
+    }
+}
@@ -1,8 +1,6 @@
 use std::thread;
 use std::time::Duration;
 use protocol::lrdp_socket::LrdpSocket;
-use log;
-use pretty_env_logger;
 
 fn main() {
     pretty_env_logger::init();
@@ -15,6 +13,8 @@ fn main() {
             log::info!("Sending...");
             socket.send_to("127.0.0.1:6860", &[1, 2, 3]).unwrap();
         }
+        log::info!("Closing...");
+        socket.close_to("127.0.0.1:6860").unwrap();
         socket.stop();
     });
 
@@ -1,112 +1,1165 @@
-use crate::lrdp_packet::LrdpPacket;
-use log;
-use std::collections::VecDeque;
+use crate::lrdp_config::LrdpConfig;
+use crate::lrdp_delivery::{Delivery, MessageId};
+use crate::lrdp_packet::{
+    ControlType, HeaderFormat, LrdpPacket, LrdpPacketRef, SELECTIVE_ACK_OPTION,
+};
+use crate::lrdp_stats::LrdpStats;
+use crate::rtt_estimator::RttEstimator;
+use std::collections::{HashMap, VecDeque};
 use std::fmt;
 use std::net::SocketAddr;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-/// The maximum sequence number possible.
-const MAX_SEQ: u8 = 8;
+pub(crate) type ClientResult<T> = Result<T, ClientError>;
 
-type ClientResult<T> = Result<T, ClientError>;
+/// The longest selective acknowledgement bitmap, which covers the window of the extended format.
+const MAX_SACK_BITMAP_LEN: usize = 32;
+
+/// Returns the sequence number which comes after `seq_num` in the sequence space of `format`.
+fn next_seq(format: HeaderFormat, seq_num: u16) -> u16 {
+    ((seq_num as u32 + 1) % format.seq_space()) as u16
+}
+
+/// Returns the sequence number which comes before `seq_num` in the sequence space of `format`.
+fn prev_seq(format: HeaderFormat, seq_num: u16) -> u16 {
+    ((seq_num as u32 + format.seq_space() - 1) % format.seq_space()) as u16
+}
+
+/// Returns how many sequence numbers `seq_num` is ahead of `base` in the sequence space of
+/// `format`.
+fn seq_offset(format: HeaderFormat, base: u16, seq_num: u16) -> u16 {
+    ((seq_num as u32 + format.seq_space() - base as u32) % format.seq_space()) as u16
+}
+
+/// A packet in a client's send queue.
+struct QueuedPacket {
+    packet: LrdpPacket,
+    /// The first time this packet was transmitted.
+    first_send: Option<Instant>,
+    /// The last time this packet was transmitted, or `None` if it is due to be transmitted.
+    last_send: Option<Instant>,
+    /// The number of times this packet has been transmitted.
+    transmissions: u32,
+    /// Whether the receiver has selectively acknowledged this packet, so it never needs to be
+    /// retransmitted.
+    sacked: bool,
+    /// The message which this packet is a fragment of.
+    message: Option<MessageId>,
+    /// When the message this packet belongs to stops being worth delivering.
+    deadline: Option<Instant>,
+    /// Whether the message this packet belongs to has been given up on, so the packet is never
+    /// sent again and the receiver is told to skip it instead.
+    abandoned: bool,
+}
+
+impl QueuedPacket {
+    fn new(packet: LrdpPacket) -> Self {
+        Self {
+            packet,
+            first_send: None,
+            last_send: None,
+            transmissions: 0,
+            sacked: false,
+            message: None,
+            deadline: None,
+            abandoned: false,
+        }
+    }
+}
+
+/// A message which was queued to be sent reliably, which is followed until all of its fragments
+/// have been acknowledged.
+struct QueuedMessage {
+    id: MessageId,
+    /// The number of fragments which have not been acknowledged yet.
+    fragments: usize,
+    /// The first time the first fragment of this message was transmitted.
+    first_send: Option<Instant>,
+    /// When this message stops being worth delivering.
+    deadline: Option<Instant>,
+    /// The key which newer messages replace this one on.
+    key: Option<u32>,
+    /// Whether the message has been given up on because it expired or was superseded, which has
+    /// already been reported.
+    abandoned: bool,
+}
+
+/// The stage of the connection with a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// A SYN has been sent but not acknowledged yet. Data can be queued but is not sent.
+    Opening,
+    /// The connection is open and data can be exchanged.
+    Open,
+    /// A FIN has been sent but not acknowledged yet.
+    Closing,
+    /// The connection has been closed by either side.
+    Closed,
+    /// The client stopped acknowledging packets, so the connection was abandoned.
+    Unreachable,
+}
 
 /// The state associated with a client connected over an LRDP socket.
 pub struct ClientState {
-    /// The address of this client.
-    addr: SocketAddr,
-    /// The sequence number of the received data.
-    remote_seq: u8,
+    /// The address of this client as a string, used as the log target.
+    target: String,
+    /// The header format, which determines the size of the sequence space.
+    format: HeaderFormat,
+    /// The sequence number of the next piece of data which can be emitted.
+    remote_seq: u16,
     /// The sequence number of the sent data.
-    local_seq: u8,
+    local_seq: u16,
     /// The last time something was received by this client.
     pub last_recv: Option<Instant>,
     /// The last time something was sent to this client.
     pub last_send: Option<Instant>,
-    /// Packets which are waiting to be sent, or have been sent and not yet acknowledged.
-    send_queue: VecDeque<LrdpPacket>,
+    /// Packets which are waiting to be sent, or have been sent and not yet acknowledged. Only the
+    /// packets inside the send window are ever in flight.
+    send_queue: VecDeque<QueuedPacket>,
+    /// Fragments of the most recent message which did not fit in the send queue. These are given
+    /// sequence numbers and moved to the send queue as space frees up.
+    pending_fragments: VecDeque<Box<[u8]>>,
+    /// When each of the last window of packets to leave the send queue was acknowledged, oldest
+    /// first.
+    acked_at: VecDeque<Instant>,
+    /// The messages which have been queued but not fully acknowledged, in the order they were
+    /// queued.
+    messages: VecDeque<QueuedMessage>,
+    /// What became of the messages which are no longer followed, waiting to be taken.
+    deliveries: Vec<(MessageId, Delivery)>,
+    /// Data which was received out of order and is waiting for the gap before it to be filled.
+    recv_buffer: HashMap<u16, (Box<[u8]>, bool)>,
+    /// The fragments of a message which have been received in order, but which are still waiting
+    /// for the last fragment.
+    partial_message: Vec<u8>,
+    /// The round trip time estimate which is used to decide when to retransmit packets.
+    rtt: RttEstimator,
+    /// Whether a packet has been retransmitted because it timed out, so the retransmission timeout
+    /// needs to be backed off once `poll_transmit` runs out of packets.
+    backoff_pending: bool,
+    /// The stage of the connection with this client.
+    state: ConnectionState,
+    /// Whether or not the connection should be closed once all queued data is acknowledged.
+    close_requested: bool,
+    /// Whether or not any data has been received from this client.
+    received_data: bool,
+    /// When the connection with this client was last opened.
+    opened_at: Option<Instant>,
+    /// How long the client keeps retransmitting a SYN or FIN which has not been acknowledged.
+    handshake_timeout: Duration,
+    /// When the SYN or FIN which is waiting to be acknowledged is given up on.
+    handshake_deadline: Option<Instant>,
+    /// A SYN or FIN which is waiting to be acknowledged.
+    control: Option<QueuedPacket>,
+    /// How long the client can go without sending anything before it is considered idle.
+    idle_timeout: Option<Duration>,
+    /// How long to go without sending anything to the client before sending a keepalive.
+    keepalive_interval: Option<Duration>,
+    /// How many times a packet can be retransmitted before the client is unreachable.
+    max_retransmissions: Option<u32>,
+    /// How long a packet can go unacknowledged before the client is unreachable.
+    max_delivery_time: Option<Duration>,
+    /// The most reliable packets which can be queued without being acknowledged.
+    max_in_flight: usize,
+    /// How long an acknowledgement can be held back while waiting for data to piggyback it on.
+    ack_delay: Option<Duration>,
+    /// The number of data packets which have been received but not acknowledged yet.
+    unacked_recv: u32,
+    /// Whether the received data should be acknowledged without any delay, because something was
+    /// received out of order.
+    ack_immediately: bool,
+    /// When a standalone acknowledgement has to be sent if there is no data to piggyback it on.
+    ack_due: Option<Instant>,
+    /// Whether selective acknowledgements are offered to the client.
+    selective_ack: bool,
+    /// Whether both sides agreed to use selective acknowledgements.
+    sack_enabled: bool,
+    /// The traffic which has been exchanged with this client.
+    stats: LrdpStats,
 }
 
 impl ClientState {
-    /// Creates a new client state associated with the given `addr`.
-    pub fn new(addr: SocketAddr) -> Self {
+    /// Creates a new client state associated with the given `addr`. The connection starts out as
+    /// open, which is the case when a SYN has been received from the client.
+    pub fn new(addr: SocketAddr, config: &LrdpConfig) -> Self {
         Self {
-            addr,
+            target: addr.to_string(),
+            format: config.header_format,
             remote_seq: 0,
             local_seq: 0,
             last_recv: None,
             last_send: None,
             send_queue: VecDeque::with_capacity(8),
+            pending_fragments: VecDeque::new(),
+            acked_at: VecDeque::new(),
+            messages: VecDeque::new(),
+            deliveries: Vec::new(),
+            recv_buffer: HashMap::new(),
+            partial_message: Vec::new(),
+            rtt: RttEstimator::new(),
+            backoff_pending: false,
+            state: ConnectionState::Open,
+            close_requested: false,
+            received_data: false,
+            opened_at: None,
+            handshake_timeout: config.handshake_timeout,
+            handshake_deadline: None,
+            control: None,
+            idle_timeout: config.idle_timeout,
+            keepalive_interval: config.keepalive_interval,
+            max_retransmissions: config.max_retransmissions,
+            max_delivery_time: config.max_delivery_time,
+            max_in_flight: config.in_flight_limit(),
+            ack_delay: config.ack_delay,
+            unacked_recv: 0,
+            ack_immediately: false,
+            ack_due: None,
+            selective_ack: config.selective_ack,
+            sack_enabled: false,
+            stats: LrdpStats::default(),
+        }
+    }
+
+    /// Starts opening the connection by queueing a SYN. Any data which is enqueued will not be
+    /// sent until the SYN has been acknowledged.
+    pub fn open(&mut self) {
+        self.state = ConnectionState::Opening;
+        // the options byte is left out when nothing is offered.
+        let data: Box<[u8]> = if self.selective_ack {
+            Box::new([self.format.id(), SELECTIVE_ACK_OPTION])
+        } else {
+            Box::new([self.format.id()])
+        };
+        let syn = LrdpPacket::control(ControlType::Syn, data);
+        self.control = Some(QueuedPacket::new(syn));
+    }
+
+    /// Agrees on the options to use for the connection, given the `options` which the client sent
+    /// in its SYN or SYN-ACK. Only options which both sides support are used.
+    pub fn negotiate(&mut self, options: u8) {
+        self.sack_enabled = self.selective_ack && options & SELECTIVE_ACK_OPTION != 0;
+    }
+
+    /// Returns the options which have been agreed on, to be sent back in a SYN-ACK.
+    pub fn options(&self) -> u8 {
+        if self.sack_enabled {
+            SELECTIVE_ACK_OPTION
+        } else {
+            0
+        }
+    }
+
+    /// Requests that the connection is closed. A FIN will be sent once all of the queued data has
+    /// been acknowledged.
+    pub fn close(&mut self) {
+        self.close_requested = true;
+    }
+
+    /// Gives up on the connection without telling the client, such as when it has been idle for too
+    /// long.
+    pub fn evict(&mut self) {
+        self.state = ConnectionState::Closed;
+        self.control = None;
+        self.lose_messages();
+    }
+
+    /// Returns the stage of the connection with this client.
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// Whether or not the client has gone longer than the idle timeout without sending anything at
+    /// `now`. A client which has never sent anything is measured from when it was last sent to.
+    pub fn is_idle(&self, now: Instant) -> bool {
+        match (self.idle_timeout, self.last_recv.or(self.last_send)) {
+            (Some(timeout), Some(last)) => now.duration_since(last) >= timeout,
+            _ => false,
+        }
+    }
+
+    /// Handles a control packet which was received at `now`, and returns the type of control packet
+    /// which should be sent in reply, if any.
+    pub fn recv_control(&mut self, control: ControlType, now: Instant) -> Option<ControlType> {
+        log::trace!(target: &self.target, "Received {:?}", control);
+        let reply = match (control, self.state) {
+            // both sides tried to open the connection at the same time.
+            (ControlType::Syn, ConnectionState::Opening) => {
+                self.state = ConnectionState::Open;
+                self.opened_at = Some(now);
+                self.control = None;
+                Some(ControlType::SynAck)
+            }
+            (ControlType::Syn, _) => {
+                // a retransmitted SYN can be overtaken by the data which followed the SYN-ACK, but
+                // the client gives up on a SYN after the handshake timeout, so only a SYN which
+                // comes later than that can be for a new connection.
+                let retransmitted = self.opened_at.is_some_and(|opened_at| {
+                    now.duration_since(opened_at) < self.handshake_timeout
+                });
+                // if data has already been received then the client has started a new connection,
+                // so the old sequence state is thrown away.
+                if (self.received_data && !retransmitted)
+                    || self.state == ConnectionState::Unreachable
+                {
+                    log::trace!(target: &self.target, "Client reopened, resetting state.");
+                    self.remote_seq = 0;
+                    self.local_seq = 0;
+                    self.send_queue.clear();
+                    self.pending_fragments.clear();
+                    self.acked_at.clear();
+                    self.lose_messages();
+                    self.recv_buffer.clear();
+                    self.partial_message.clear();
+                    self.clear_pending_ack();
+                    self.received_data = false;
+                    self.state = ConnectionState::Open;
+                    self.opened_at = Some(now);
+                }
+                self.opened_at.get_or_insert(now);
+                Some(ControlType::SynAck)
+            }
+            (ControlType::SynAck, ConnectionState::Opening) => {
+                self.ack_control(now);
+                self.state = ConnectionState::Open;
+                self.opened_at = Some(now);
+                None
+            }
+            (ControlType::Fin, _) => {
+                self.state = ConnectionState::Closed;
+                self.control = None;
+                self.lose_messages();
+                Some(ControlType::FinAck)
+            }
+            (ControlType::FinAck, ConnectionState::Closing) => {
+                self.ack_control(now);
+                self.state = ConnectionState::Closed;
+                None
+            }
+            // keepalives only need to update when the client was last heard from.
+            (ControlType::Keepalive, _) => None,
+            // anything else is a duplicate, which can be ignored.
+            _ => None,
+        };
+        if !matches!(
+            self.state,
+            ConnectionState::Opening | ConnectionState::Closing
+        ) {
+            self.handshake_deadline = None;
+        }
+        reply
+    }
+
+    /// Gives up on the connection if the SYN or FIN which is waiting to be acknowledged has gone
+    /// unacknowledged for longer than the handshake timeout at `now`. In that case all of the
+    /// queued data is discarded and `ClientError::Unreachable` is returned.
+    pub fn handle_timeout(&mut self, now: Instant) -> ClientResult<()> {
+        if self
+            .handshake_deadline
+            .is_some_and(|deadline| now >= deadline)
+        {
+            log::error!(
+                target: &self.target,
+                "Handshake timed out, client is unreachable."
+            );
+            self.set_unreachable();
+            return Err(ClientError::Unreachable);
+        }
+        Ok(())
+    }
+
+    /// Removes the pending control packet, which was acknowledged at `now`.
+    fn ack_control(&mut self, now: Instant) {
+        if let Some(QueuedPacket {
+            transmissions: 1,
+            last_send: Some(last_send),
+            ..
+        }) = self.control
+        {
+            self.rtt.sample(now.duration_since(last_send));
         }
+        self.control = None;
     }
 
     /// Acknowledges all of the packets up to and including the one with the sequence number of
-    /// `ack_num`.
-    pub fn ack(&mut self, ack_num: u8) -> ClientResult<()> {
-        log::trace!(target: &self.addr.to_string(), "Acking {}", ack_num);
-        // make sure the ack number is actually in the queue.
-        let has_num = self.send_queue.iter().any(|p| p.seq_num() == ack_num);
-        if !has_num {
-            // if the receiver was expecting the next number in the sequence, then this client is
-            // just exhausted.
-            if ack_num == self.local_seq {
-                log::trace!(target: &self.addr.to_string(), "Client exhausted, draining queue ({} items).", self.send_queue.len());
-                // drain the queue.
-                while !self.send_queue.is_empty() {
-                    self.send_queue.pop_front();
+    /// `ack_num`, which was received at `now`.
+    pub fn ack(&mut self, ack_num: u16, now: Instant) -> ClientResult<()> {
+        self.process_ack(ack_num, now, true)
+    }
+
+    /// Acknowledges all of the packets up to and including the one with the sequence number of
+    /// `ack_num`, as well as the packets after it which are marked in the selective
+    /// acknowledgement `bitmap`. Selectively acknowledged packets are never retransmitted, and any
+    /// packets before them which have only been sent once are assumed to be lost and retransmitted
+    /// straight away. The bitmap is ignored unless selective acknowledgements were negotiated.
+    pub fn selective_ack(&mut self, ack_num: u16, bitmap: &[u8], now: Instant) -> ClientResult<()> {
+        let result = self.ack(ack_num, now);
+        if !self.sack_enabled
+            || bitmap.is_empty()
+            || matches!(result, Err(ClientError::WrongAck(_)))
+        {
+            return result;
+        }
+        let format = self.format;
+        let mut last_sacked = None;
+        for (i, queued) in self.send_queue.iter_mut().enumerate() {
+            // the packet straight after the ack number is the one which is missing.
+            let offset = seq_offset(format, ack_num, queued.packet.seq_num()) as usize;
+            if offset < 2 {
+                continue;
+            }
+            let n = offset - 2;
+            if bitmap
+                .get(n / 8)
+                .is_some_and(|byte| byte & (1 << (n % 8)) != 0)
+            {
+                queued.sacked = true;
+                last_sacked = Some(i);
+            }
+        }
+        if let Some(last_sacked) = last_sacked {
+            for queued in self.send_queue.iter_mut().take(last_sacked) {
+                if !queued.sacked && queued.transmissions == 1 {
+                    queued.last_send = None;
+                }
+            }
+        }
+        result
+    }
+
+    /// Acknowledges all of the packets up to and including the one with the sequence number of
+    /// `ack_num`, which was piggybacked on a data packet received at `now`. Every data packet
+    /// carries the latest ack, so a repeated ack is expected here and does not mean that anything
+    /// was lost.
+    pub fn ack_piggybacked(&mut self, ack_num: u16, now: Instant) -> ClientResult<()> {
+        match self.process_ack(ack_num, now, false) {
+            Err(ClientError::DuplicateAck(_)) | Err(ClientError::Exhausted) => Ok(()),
+            result => result,
+        }
+    }
+
+    fn process_ack(
+        &mut self,
+        ack_num: u16,
+        now: Instant,
+        fast_retransmit: bool,
+    ) -> ClientResult<()> {
+        log::trace!(target: &self.target, "Acking {}", ack_num);
+        self.stats.acks_received += 1;
+        // make sure the ack number is actually in flight. a packet which has not been sent yet
+        // cannot have been received, so an ack for its sequence number is left over from the
+        // previous lap of the sequence space.
+        let position = self
+            .send_queue
+            .iter()
+            .take(self.format.window_size() as usize)
+            .position(|p| p.packet.seq_num() == ack_num && p.transmissions > 0);
+        match position {
+            Some(position) => {
+                // only take an rtt sample if the acked packet was not retransmitted, otherwise
+                // there is no way to know which transmission is being acked.
+                let acked = &self.send_queue[position];
+                if let (1, Some(last_send)) = (acked.transmissions, acked.last_send) {
+                    self.rtt.sample(now.duration_since(last_send));
                 }
+                // remove all packets up to and including the acked one.
+                for queued in self.send_queue.drain(..=position) {
+                    log::trace!(
+                        target: &self.target,
+                        "Removing packet {}",
+                        queued.packet.seq_num()
+                    );
+                    self.acked_at.push_back(now);
+                    if self.acked_at.len() > self.format.window_size() as usize {
+                        self.acked_at.pop_front();
+                    }
+                    // packets which were queued on their own do not belong to a message.
+                    let message = match self.messages.front_mut() {
+                        Some(message) => message,
+                        None => continue,
+                    };
+                    message.first_send = message.first_send.or(queued.first_send);
+                    message.fragments -= 1;
+                    if message.fragments == 0 {
+                        // an abandoned message was reported when it was given up on.
+                        if !message.abandoned {
+                            let latency =
+                                now.saturating_duration_since(message.first_send.unwrap_or(now));
+                            self.deliveries
+                                .push((message.id, Delivery::Acknowledged(latency)));
+                        }
+                        self.messages.pop_front();
+                    }
+                }
+                self.fill_queue();
+                Ok(())
+            }
+            // if the receiver has acknowledged the most recent packet then this client is just
+            // exhausted.
+            None if self.send_queue.is_empty()
+                && ack_num == prev_seq(self.format, self.local_seq) =>
+            {
+                log::trace!(target: &self.target, "Client exhausted.");
                 Err(ClientError::Exhausted)
-            } else {
-                log::trace!(target: &self.addr.to_string(), "Got wrong ack, local seq num is {}", self.local_seq);
-                Err(ClientError::WrongAck(ack_num))
             }
-        } else {
-            // remove all packets until we reach the acked one.
-            while let Some(packet) = self.send_queue.pop_front() {
-                log::trace!(
-                    target: &self.addr.to_string(),
-                    "Removing packet {}",
-                    packet.seq_num()
-                );
-                if packet.seq_num() == ack_num {
-                    break;
+            // if the receiver has acknowledged the packet just before the front of the queue then
+            // it is still missing the front packet, even though it has received something after it.
+            None if self
+                .send_queue
+                .front()
+                .is_some_and(|front| ack_num == prev_seq(self.format, front.packet.seq_num())) =>
+            {
+                let front = self.send_queue.front_mut().unwrap();
+                // only retransmit straight away once, otherwise every out of order packet would
+                // cause another retransmission.
+                if fast_retransmit {
+                    self.stats.duplicate_acks += 1;
+                }
+                if fast_retransmit && front.transmissions == 1 {
+                    log::trace!(
+                        target: &self.target,
+                        "Duplicate ack {}, retransmitting {}",
+                        ack_num,
+                        front.packet.seq_num()
+                    );
+                    front.last_send = None;
                 }
+                Err(ClientError::DuplicateAck(ack_num))
+            }
+            None => {
+                log::trace!(target: &self.target, "Got wrong ack, local seq num is {}", self.local_seq);
+                self.stats.wrong_acks += 1;
+                Err(ClientError::WrongAck(ack_num))
             }
-            Ok(())
         }
     }
 
+    /// Returns when the packet at `index` in the send queue can be sent for the first time, if it
+    /// has to wait. The compact sequence space is so small that a sequence number comes round
+    /// again while acks and retransmissions from its previous lap can still be on their way, and
+    /// those would be taken for ones of the new packet. So a packet is only sent once the packet a
+    /// window before it was acknowledged at least a retransmission timeout ago, by which time
+    /// everything from the previous lap has either arrived or been lost.
+    fn reuse_at(&self, index: usize) -> Option<Instant> {
+        if self.format != HeaderFormat::Compact {
+            return None;
+        }
+        let behind =
+            (self.acked_at.len() + index).checked_sub(self.format.window_size() as usize)?;
+        self.acked_at
+            .get(behind)
+            .map(|acked_at| *acked_at + self.rtt.rto())
+    }
+
+    /// Writes the selective acknowledgement bitmap of the data which has been received out of
+    /// order into `bitmap`, and returns how many bytes of it are used.
+    fn sack_bitmap(&self, bitmap: &mut [u8; MAX_SACK_BITMAP_LEN]) -> usize {
+        let mut len = 0;
+        for &seq_num in self.recv_buffer.keys() {
+            // the packet at the remote sequence number is missing, so the bitmap starts after it.
+            let n = (seq_offset(self.format, self.remote_seq, seq_num) - 1) as usize;
+            bitmap[n / 8] |= 1 << (n % 8);
+            len = len.max(n / 8 + 1);
+        }
+        len
+    }
+
+    /// Forgets about any received data which is waiting to be acknowledged.
+    fn clear_pending_ack(&mut self) {
+        self.unacked_recv = 0;
+        self.ack_immediately = false;
+        self.ack_due = None;
+    }
+
+    /// Returns the traffic which has been exchanged with this client so far, along with its current
+    /// queue depth and round trip time.
+    pub fn stats(&self) -> LrdpStats {
+        LrdpStats {
+            queue_depth: self.send_queue.len() + self.pending_fragments.len(),
+            srtt: self.rtt.srtt(),
+            ..self.stats
+        }
+    }
+
+    /// Counts a datagram of `len` bytes which was sent to this client.
+    pub fn record_sent(&mut self, len: usize) {
+        self.stats.wire_bytes_sent += len as u64;
+    }
+
+    /// Counts a datagram of `len` bytes which was received from this client.
+    pub fn record_received(&mut self, len: usize) {
+        self.stats.wire_bytes_received += len as u64;
+    }
+
     /// Returns the packet at the front of the send queue.
+    #[cfg(test)]
     pub fn next_packet(&self) -> Option<&LrdpPacket> {
-        self.send_queue.front()
+        self.send_queue.front().map(|queued| &queued.packet)
     }
 
-    /// Tries to receive the given sequence number. If the received sequence number is not the
-    /// expected one, `ClientError::WrongSeq` will be returned.
-    pub fn recv(&mut self, seq_num: u8) -> ClientResult<()> {
-        if seq_num == self.remote_seq {
-            self.remote_seq = (self.remote_seq + 1) % MAX_SEQ;
-            Ok(())
+    /// Returns the round trip time estimate for this client.
+    pub fn rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    /// Writes the next packet which needs to be transmitted at `now` to the start of `buf`, and
+    /// returns its length, or `None` once there is nothing more to send. Packets in the send window
+    /// which have not been sent yet, or were last sent longer than the retransmission timeout ago,
+    /// come first, along with pending SYN, FIN and FORWARD packets. These are followed by a
+    /// standalone ACK if one is due, or a keepalive if nothing has been sent for a while. This
+    /// should be called until it returns `None`, since the retransmission timeout is only backed
+    /// off once everything which timed out has been retransmitted.
+    ///
+    /// If a packet needs to be retransmitted but has already hit the retransmission limits, all of
+    /// the queued data is discarded and `ClientError::Unreachable` is returned.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` is too short for the packet, which never happens if it can hold the largest
+    /// datagram the connection was configured to send.
+    pub fn poll_transmit(&mut self, now: Instant, buf: &mut [u8]) -> ClientResult<Option<usize>> {
+        self.abandon_expired(now);
+        // the receiver is told to skip the packets at the front of the queue which were given up
+        // on, and the FORWARD is replaced whenever that changes.
+        if self.state == ConnectionState::Open {
+            let forward = self.forward_seq();
+            let current = self
+                .control
+                .as_ref()
+                .and_then(|queued| queued.packet.forward_seq());
+            if forward != current {
+                self.control =
+                    forward.map(|seq_num| QueuedPacket::new(LrdpPacket::forward(seq_num)));
+            }
+        }
+        // the FIN is only sent once everything before it has been acknowledged.
+        if self.close_requested && self.state == ConnectionState::Open && self.send_queue.is_empty()
+        {
+            self.state = ConnectionState::Closing;
+            let fin = LrdpPacket::control(ControlType::Fin, Box::new([]));
+            self.control = Some(QueuedPacket::new(fin));
+        }
+        // the handshake timeout counts from when the SYN or FIN is first sent.
+        if matches!(
+            self.state,
+            ConnectionState::Opening | ConnectionState::Closing
+        ) {
+            self.handshake_deadline
+                .get_or_insert(now + self.handshake_timeout);
+        }
+        let window = match self.state {
+            ConnectionState::Open | ConnectionState::Closing => self.format.window_size(),
+            _ => 0,
+        };
+
+        // everything which has been received so far is acknowledged on every data packet which is
+        // sent, unless there is no room for the ack in the header.
+        // when selective acknowledgements are in use, a gap in the received data is reported with a
+        // standalone ack instead, since only those can carry the bitmap.
+        let format = self.format;
+        let reports_gap = self.sack_enabled && !self.recv_buffer.is_empty();
+        let piggyback_ack = if self.received_data && !reports_gap {
+            Some(self.recv_ack_num())
+        } else {
+            None
+        };
+
+        // packets which are waiting for their sequence number to be safe to reuse are held back,
+        // and the later ones have to wait at least as long.
+        let held = (0..window as usize)
+            .find(|&index| self.reuse_at(index).is_some_and(|reuse_at| now < reuse_at))
+            .unwrap_or(window as usize);
+        let rto = self.rtt.rto();
+        let data = self
+            .send_queue
+            .iter_mut()
+            .take(window as usize)
+            .enumerate()
+            .filter(|(index, queued)| *index < held || queued.transmissions > 0)
+            .map(|(_, queued)| queued);
+        let next = self.control.iter_mut().chain(data).find(|queued| {
+            !queued.sacked
+                && !queued.abandoned
+                && queued
+                    .last_send
+                    .is_none_or(|last_send| now.duration_since(last_send) >= rto)
+        });
+        if let Some(queued) = next {
+            // packets which are due again without having timed out are fast retransmissions.
+            let timed_out = queued.last_send.is_some();
+            if queued.transmissions > 0 {
+                let too_many = self
+                    .max_retransmissions
+                    .is_some_and(|max| queued.transmissions > max);
+                let too_old = match (self.max_delivery_time, queued.first_send) {
+                    (Some(max), Some(first_send)) => now.duration_since(first_send) >= max,
+                    _ => false,
+                };
+                if too_many || too_old {
+                    log::error!(
+                        target: &self.target,
+                        "Giving up after {} transmissions, client is unreachable.",
+                        queued.transmissions
+                    );
+                    self.set_unreachable();
+                    return Err(ClientError::Unreachable);
+                }
+                match queued.packet.control_type() {
+                    Some(control) => log::warn!(
+                        target: &self.target,
+                        "Retransmitting {:?} ({} previous transmissions).",
+                        control,
+                        queued.transmissions
+                    ),
+                    None => log::warn!(
+                        target: &self.target,
+                        "Retransmitting packet with seq {} ({} previous transmissions).",
+                        queued.packet.seq_num(),
+                        queued.transmissions
+                    ),
+                }
+                self.stats.retransmissions += 1;
+                self.backoff_pending |= timed_out;
+            } else if queued.packet.has_data() {
+                self.stats.data_packets_sent += 1;
+                self.stats.payload_bytes_sent += queued.packet.data().len() as u64;
+            }
+            let mut piggybacked = false;
+            if queued.packet.has_data() {
+                let has_room = format != HeaderFormat::Compact || !queued.packet.more_fragments();
+                let ack_num = piggyback_ack.filter(|_| has_room);
+                queued.packet.set_ack_num(ack_num);
+                if ack_num.is_some() {
+                    self.stats.piggybacked_acks += 1;
+                    piggybacked = true;
+                }
+            }
+            queued.first_send.get_or_insert(now);
+            queued.last_send = Some(now);
+            queued.transmissions += 1;
+            let len = queued.packet.encode_into(format, buf);
+            if piggybacked {
+                self.clear_pending_ack();
+            }
+            self.last_send = Some(now);
+            return Ok(Some(len));
+        }
+
+        // send a standalone ack if there was no data to piggyback it on in time.
+        if self.unacked_recv > 0 {
+            let ack_now = self.ack_immediately || self.unacked_recv >= 2;
+            let due = match self.ack_delay {
+                Some(delay) if !ack_now => *self.ack_due.get_or_insert(now + delay),
+                _ => now,
+            };
+            if now >= due {
+                let mut bitmap = [0u8; MAX_SACK_BITMAP_LEN];
+                let bitmap_len = if self.sack_enabled {
+                    self.sack_bitmap(&mut bitmap)
+                } else {
+                    0
+                };
+                let ack = LrdpPacketRef::selective_ack(self.recv_ack_num(), &bitmap[..bitmap_len]);
+                let len = ack.encode_into(format, buf);
+                self.stats.acks_sent += 1;
+                self.clear_pending_ack();
+                self.last_send = Some(now);
+                return Ok(Some(len));
+            }
+        }
+        // send a keepalive if nothing has been sent for a while.
+        let keepalive_due = self.keepalive_interval.is_some_and(|interval| {
+            self.last_send
+                .is_some_and(|last_send| now.duration_since(last_send) >= interval)
+        });
+        if keepalive_due && self.state == ConnectionState::Open {
+            log::trace!(target: &self.target, "Sending keepalive.");
+            let keepalive = LrdpPacketRef::control(ControlType::Keepalive, &[]);
+            self.last_send = Some(now);
+            return Ok(Some(keepalive.encode_into(format, buf)));
+        }
+        // everything which timed out has been retransmitted, so wait longer before the next try.
+        if self.backoff_pending {
+            self.rtt.backoff();
+            self.backoff_pending = false;
+        }
+        Ok(None)
+    }
+
+    /// Returns the next time at which `poll_transmit` or `handle_timeout` needs to be called, or
+    /// `None` if there is nothing to wait for. This is the earliest of the retransmission timeouts
+    /// of the packets which are in flight, the time at which a packet which is held back can be
+    /// sent, the next keepalive, the delayed acknowledgement, the deadline of the next message to
+    /// expire, the time at which the client becomes idle, and the time at which the handshake is
+    /// given up on. It should be called after `poll_transmit`, since packets which have not been
+    /// sent yet are not taken into account.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        let window = match self.state {
+            ConnectionState::Open | ConnectionState::Closing => self.format.window_size(),
+            _ => 0,
+        };
+        let rto = self.rtt.rto();
+        let data = self.send_queue.iter().take(window as usize);
+        let retransmit = self
+            .control
+            .iter()
+            .chain(data)
+            .filter(|queued| !queued.sacked && !queued.abandoned)
+            .filter_map(|queued| queued.last_send)
+            .map(|last_send| last_send + rto)
+            .min();
+        let reuse = self
+            .send_queue
+            .iter()
+            .take(window as usize)
+            .position(|queued| queued.transmissions == 0 && !queued.abandoned)
+            .and_then(|index| self.reuse_at(index));
+        let deadline = self
+            .messages
+            .iter()
+            .filter(|message| !message.abandoned)
+            .filter_map(|message| message.deadline)
+            .min();
+        let keepalive = match (self.state, self.keepalive_interval, self.last_send) {
+            (ConnectionState::Open, Some(interval), Some(last_send)) => Some(last_send + interval),
+            _ => None,
+        };
+        let idle = match (self.idle_timeout, self.last_recv.or(self.last_send)) {
+            (Some(timeout), Some(last)) => Some(last + timeout),
+            _ => None,
+        };
+        [
+            retransmit,
+            reuse,
+            keepalive,
+            idle,
+            deadline,
+            self.ack_due,
+            self.handshake_deadline,
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .copied()
+    }
+
+    /// Tries to receive the given sequence number. Data which is received within the receive
+    /// window is buffered until all of the data before it has been received, at which point all of
+    /// the messages which can be emitted in order are returned. If `more_fragments` is set, the
+    /// data is the start of a message which continues in the next sequence number, and it is only
+    /// emitted once the rest of the message has been received. If the received sequence number is
+    /// outside of the receive window, `ClientError::WrongSeq` will be returned.
+    pub fn recv(
+        &mut self,
+        seq_num: u16,
+        data: &[u8],
+        more_fragments: bool,
+    ) -> ClientResult<Vec<Box<[u8]>>> {
+        // anything which is not the next packet in order is acknowledged straight away, so that the
+        // sender finds out what is missing as soon as possible.
+        self.unacked_recv += 1;
+        let offset = seq_offset(self.format, self.remote_seq, seq_num);
+        if offset >= self.format.window_size() {
+            self.ack_immediately = true;
+            self.stats.wrong_seqs += 1;
+            // a packet just behind the window has been received before, and its ack was lost.
+            if offset as u32 >= self.format.seq_space() - self.format.window_size() as u32 {
+                self.stats.duplicate_packets += 1;
+            }
+            return Err(ClientError::WrongSeq(seq_num, self.remote_seq));
+        }
+        self.ack_immediately |= seq_num != self.remote_seq || !self.recv_buffer.is_empty();
+        if self.recv_buffer.contains_key(&seq_num) {
+            self.stats.duplicate_packets += 1;
         } else {
-            Err(ClientError::WrongSeq(seq_num, self.remote_seq))
+            if seq_num != self.remote_seq {
+                self.stats.out_of_order_packets += 1;
+            }
+            self.stats.payload_bytes_received += data.len() as u64;
+        }
+        self.recv_buffer
+            .insert(seq_num, (data.into(), more_fragments));
+        self.received_data = true;
+        Ok(self.emit_in_order())
+    }
+
+    /// Stops waiting for the data before `seq_num`, which the sender has given up on. Anything
+    /// before it which was received out of order is thrown away along with any partly received
+    /// message, and the messages after it which can now be emitted in order are returned. A
+    /// `seq_num` which is not ahead of the data received so far has already been skipped to, and
+    /// is only acknowledged again.
+    pub fn skip_to(&mut self, seq_num: u16) -> Vec<Box<[u8]>> {
+        self.unacked_recv += 1;
+        self.ack_immediately = true;
+        let offset = seq_offset(self.format, self.remote_seq, seq_num);
+        if offset == 0 || offset > self.format.window_size() {
+            return Vec::new();
+        }
+        log::trace!(target: &self.target, "Skipping to {}", seq_num);
+        while self.remote_seq != seq_num {
+            self.recv_buffer.remove(&self.remote_seq);
+            self.remote_seq = next_seq(self.format, self.remote_seq);
         }
+        self.partial_message.clear();
+        self.received_data = true;
+        self.emit_in_order()
+    }
+
+    /// Removes the data which can be emitted in order from the receive buffer, and returns the
+    /// messages which have been completed.
+    fn emit_in_order(&mut self) -> Vec<Box<[u8]>> {
+        let mut emitted = Vec::new();
+        while let Some((data, more_fragments)) = self.recv_buffer.remove(&self.remote_seq) {
+            self.remote_seq = next_seq(self.format, self.remote_seq);
+            if more_fragments {
+                self.partial_message.extend_from_slice(&data);
+            } else if self.partial_message.is_empty() {
+                emitted.push(data);
+            } else {
+                self.partial_message.extend_from_slice(&data);
+                emitted.push(std::mem::take(&mut self.partial_message).into_boxed_slice());
+            }
+        }
+        emitted
+    }
+
+    /// Returns the sequence number of the last piece of data which was received in order. This is
+    /// the number which should be used to acknowledge received data.
+    pub fn recv_ack_num(&self) -> u16 {
+        prev_seq(self.format, self.remote_seq)
     }
 
     /// Returns the next local sequence number.
-    pub fn next_seq_num(&self) -> u8 {
+    #[cfg(test)]
+    pub fn next_seq_num(&self) -> u16 {
         self.local_seq
     }
 
-    /// Tries to add the `packet` to this client state's send queue. If the sequence number of the
-    /// packet is not the expected one, `ClientError::WrongSeq` is returned.
+    /// Whether the send queue holds as many packets as can be in flight at once, or there are still
+    /// fragments of a message waiting to be queued. Nothing more can be enqueued until some of
+    /// them have been acknowledged.
+    pub fn is_full(&self) -> bool {
+        !self.pending_fragments.is_empty() || self.send_queue.len() >= self.max_in_flight
+    }
+
+    /// The number of packets in the send queue, including those which have not been sent yet.
+    pub fn queued_len(&self) -> usize {
+        self.send_queue.len()
+    }
+
+    /// Whether any data is queued for this client which it has not acknowledged yet.
+    pub fn has_queued_data(&self) -> bool {
+        !self.send_queue.is_empty() || !self.pending_fragments.is_empty()
+    }
+
+    /// Discards everything which is queued for this client, and returns the messages which it has
+    /// not acknowledged. If the start of a message has already been acknowledged, only the rest of
+    /// it is returned. Messages which have expired or been superseded are left out, since they are
+    /// no longer wanted.
+    pub fn take_undelivered(&mut self) -> Vec<Box<[u8]>> {
+        if self
+            .messages
+            .back()
+            .is_some_and(|message| message.abandoned)
+        {
+            self.pending_fragments.clear();
+        }
+        self.lose_messages();
+        let mut messages = Vec::new();
+        let mut message = Vec::new();
+        for queued in self.send_queue.drain(..) {
+            if queued.abandoned {
+                continue;
+            }
+            message.extend_from_slice(queued.packet.data());
+            if !queued.packet.more_fragments() {
+                messages.push(std::mem::take(&mut message).into_boxed_slice());
+            }
+        }
+        // the pending fragments are the rest of the last message in the send queue.
+        if !message.is_empty() || !self.pending_fragments.is_empty() {
+            for fragment in self.pending_fragments.drain(..) {
+                message.extend_from_slice(&fragment);
+            }
+            messages.push(message.into_boxed_slice());
+        }
+        messages
+    }
+
+    /// Tries to add the `packet` to this client state's send queue. If the queue is full,
+    /// `ClientError::QueueFull` is returned. If the sequence number of the packet is not the
+    /// expected one, `ClientError::WrongSeq` is returned. The packet will be
+    /// transmitted the next time `poll_transmit` is called once it is inside the send window.
+    #[cfg(test)]
     pub fn enqueue(&mut self, packet: LrdpPacket) -> ClientResult<()> {
-        if packet.seq_num() != self.local_seq {
+        if self.is_full() {
+            Err(ClientError::QueueFull)
+        } else if packet.seq_num() != self.local_seq {
             Err(ClientError::WrongSeq(packet.seq_num(), self.local_seq))
         } else {
-            self.send_queue.push_back(packet);
-            self.local_seq = (self.local_seq + 1) % MAX_SEQ;
+            self.send_queue.push_back(QueuedPacket::new(packet));
+            self.local_seq = next_seq(self.format, self.local_seq);
             Ok(())
         }
     }
+
+    /// Tries to add a whole message to this client state's send queue, splitting it into fragments
+    /// of at most `max_fragment_size` bytes. If the queue is full, `ClientError::QueueFull` is
+    /// returned. Otherwise the message is accepted even if not all of its fragments fit in the
+    /// queue, and the rest are queued as acknowledgements free up space.
+    ///
+    /// If a `deadline` is given, the message is given up on once it passes, and the receiver is told
+    /// to stop waiting for it. If a `key` is given, the older messages with the same key which have
+    /// not been fully acknowledged are given up on in the same way, since this one replaces them.
+    pub fn enqueue_message(
+        &mut self,
+        data: &[u8],
+        max_fragment_size: usize,
+        deadline: Option<Instant>,
+        key: Option<u32>,
+    ) -> ClientResult<MessageId> {
+        if self.is_full() {
+            return Err(ClientError::QueueFull);
+        }
+        if let Some(key) = key {
+            self.supersede(key);
+        }
+        let fragments_before = self.pending_fragments.len();
+        if data.is_empty() {
+            self.pending_fragments.push_back(Box::new([]));
+        }
+        for fragment in data.chunks(max_fragment_size.max(1)) {
+            self.pending_fragments.push_back(fragment.into());
+        }
+        let id = MessageId::next();
+        self.messages.push_back(QueuedMessage {
+            id,
+            fragments: self.pending_fragments.len() - fragments_before,
+            first_send: None,
+            deadline,
+            key,
+            abandoned: false,
+        });
+        self.fill_queue();
+        Ok(id)
+    }
+
+    /// Whether the message with the given `id` has been queued but not fully acknowledged yet.
+    pub fn is_pending(&self, id: MessageId) -> bool {
+        self.messages.iter().any(|message| message.id == id)
+    }
+
+    /// Returns what became of the messages which have been acknowledged or given up on since this
+    /// was last called.
+    pub fn take_deliveries(&mut self) -> Vec<(MessageId, Delivery)> {
+        std::mem::take(&mut self.deliveries)
+    }
+
+    /// Declares the client unreachable, and discards everything which is queued for it or has been
+    /// received from it.
+    fn set_unreachable(&mut self) {
+        self.state = ConnectionState::Unreachable;
+        self.send_queue.clear();
+        self.pending_fragments.clear();
+        self.lose_messages();
+        self.recv_buffer.clear();
+        self.partial_message.clear();
+        self.clear_pending_ack();
+        self.control = None;
+        self.handshake_deadline = None;
+        self.backoff_pending = false;
+    }
+
+    /// Gives up on every message which has not been fully acknowledged. Messages which have
+    /// expired or been superseded were already reported when they were given up on.
+    fn lose_messages(&mut self) {
+        for message in self.messages.drain(..) {
+            if !message.abandoned {
+                self.deliveries.push((message.id, Delivery::Lost));
+            }
+        }
+    }
+
+    /// Gives up on the messages whose deadline has passed at `now`. Their packets are never sent
+    /// again, and once they reach the front of the send queue the receiver is told to skip them.
+    fn abandon_expired(&mut self, now: Instant) {
+        for message in self.messages.iter_mut() {
+            if !message.abandoned && message.deadline.is_some_and(|deadline| now >= deadline) {
+                log::debug!(
+                    target: &self.target,
+                    "Message {:?} expired, giving up on it.",
+                    message.id
+                );
+                message.abandoned = true;
+                self.deliveries.push((message.id, Delivery::Expired));
+            }
+        }
+        // fragments which were moved to the send queue after their message expired are abandoned
+        // as well.
+        if self.messages.iter().any(|message| message.abandoned) {
+            for queued in self.send_queue.iter_mut() {
+                queued.abandoned |= queued.deadline.is_some_and(|deadline| now >= deadline);
+            }
+        }
+    }
+
+    /// Gives up on the messages with the given `key` which have not been fully acknowledged, since
+    /// a newer message replaces them. All of their fragments are already in the send queue, since
+    /// nothing can be enqueued while there are pending fragments.
+    fn supersede(&mut self, key: u32) {
+        let superseded = self
+            .messages
+            .iter_mut()
+            .filter(|message| !message.abandoned && message.key == Some(key));
+        for message in superseded {
+            log::debug!(
+                target: &self.target,
+                "Message {:?} was superseded, giving up on it.",
+                message.id
+            );
+            message.abandoned = true;
+            self.deliveries.push((message.id, Delivery::Superseded));
+            for queued in self.send_queue.iter_mut() {
+                queued.abandoned |= queued.message == Some(message.id);
+            }
+        }
+    }
+
+    /// Returns the sequence number which the receiver should skip to, because the packets before
+    /// it at the front of the send queue have been given up on.
+    fn forward_seq(&self) -> Option<u16> {
+        let front = self.send_queue.front()?.packet.seq_num();
+        let skipped = self
+            .send_queue
+            .iter()
+            .take_while(|queued| queued.abandoned)
+            .count();
+        if skipped == 0 {
+            None
+        } else {
+            Some(((front as u32 + skipped as u32) % self.format.seq_space()) as u16)
+        }
+    }
+
+    /// Moves as many pending fragments into the send queue as there is space for.
+    fn fill_queue(&mut self) {
+        while self.send_queue.len() < self.max_in_flight {
+            let fragment = match self.pending_fragments.pop_front() {
+                Some(fragment) => fragment,
+                None => break,
+            };
+            let mut packet = LrdpPacket::create(fragment, None, Some(self.local_seq));
+            if !self.pending_fragments.is_empty() {
+                packet = packet.with_more_fragments();
+            }
+            let mut queued = QueuedPacket::new(packet);
+            if let Some(message) = self.messages.back() {
+                queued.message = Some(message.id);
+                queued.deadline = message.deadline;
+            }
+            self.send_queue.push_back(queued);
+            self.local_seq = next_seq(self.format, self.local_seq);
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -114,15 +1167,26 @@ pub enum ClientError {
     /// The client received an acknowledgement number that it did not expect to receive. This most
     /// likely happens when the remote state is very far out of sync with the local state. In this
     /// situation there isn't much that can be done other than to bail.
-    WrongAck(u8),
+    WrongAck(u16),
+    /// The client received an acknowledgement number for a packet which has already been
+    /// acknowledged. This means that the receiver is still missing the packet at the front of the
+    /// send queue, but has received something after it.
+    DuplicateAck(u16),
     /// The client received a sequence number that it did not expect to receive. The first element
     /// of this tuple is the received sequence number and the second element is the expected
     /// sequence number.
-    WrongSeq(u8, u8),
+    WrongSeq(u16, u16),
     /// The receiver has acknowledged all of the data in the client's send queue, and there is no
     /// more data to send. This is caused when the client does not hear an ACK and thus retransmits
     /// the latest packet even though the receiver is expecting the next packet in the sequence.
     Exhausted,
+    /// A packet was retransmitted too many times or went unacknowledged for too long, so the
+    /// client is assumed to be unreachable.
+    Unreachable,
+    /// The send queue already holds as many packets as can be in flight at once. If more packets
+    /// were queued, their sequence numbers would collide with ones which have not been
+    /// acknowledged yet.
+    QueueFull,
 }
 
 impl fmt::Display for ClientError {
@@ -133,10 +1197,13 @@ impl fmt::Display for ClientError {
                 "Received ACK number {} but there is no corresponding unacknowledged packet.",
                 a
             ),
+            Self::DuplicateAck(a) => write!(f, "Received duplicate ACK number {}.", a),
             Self::WrongSeq(actual, expected) => {
                 write!(f, "Expected sequence number {}, got {}.", expected, actual)
             }
             Self::Exhausted => write!(f, "The client's send queue is exhausted."),
+            Self::Unreachable => write!(f, "The client is unreachable."),
+            Self::QueueFull => write!(f, "The client's send queue is full."),
         }
     }
 }
@@ -147,50 +1214,119 @@ impl std::error::Error for ClientError {}
 mod tests {
     use super::*;
 
+    /// Returns every datagram which `state` has to send at `now`.
+    fn transmit(state: &mut ClientState, now: Instant) -> ClientResult<Vec<Vec<u8>>> {
+        let mut buf = [0u8; u16::MAX as usize];
+        let mut buffers = Vec::new();
+        while let Some(len) = state.poll_transmit(now, &mut buf)? {
+            buffers.push(buf[..len].to_vec());
+        }
+        Ok(buffers)
+    }
+
     #[test]
     fn enqueue_good_seq_num() {
         let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
-        let mut state = ClientState::new(addr);
-        assert!(matches!(
-            state.enqueue(LrdpPacket::create(Box::new([]), None, Some(0))),
-            Ok(_)
-        ));
+        let mut state = ClientState::new(addr, &LrdpConfig::default());
+        assert!(state
+            .enqueue(LrdpPacket::create(Box::new([]), None, Some(0)))
+            .is_ok());
     }
 
     #[test]
     fn enqueue_bad_seq_num() {
         let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
-        let mut state = ClientState::new(addr);
+        let mut state = ClientState::new(addr, &LrdpConfig::default());
         assert!(matches!(
             state.enqueue(LrdpPacket::create(Box::new([]), None, Some(2))),
             Err(ClientError::WrongSeq(2, 0))
         ));
     }
 
+    #[test]
+    fn enqueue_queue_full() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let mut state = ClientState::new(addr, &LrdpConfig::default());
+        // the compact format can only have 4 packets in flight.
+        for i in 0..4 {
+            state
+                .enqueue(LrdpPacket::create(Box::new([]), None, Some(i)))
+                .unwrap();
+        }
+        assert!(state.is_full());
+        assert!(matches!(
+            state.enqueue(LrdpPacket::create(Box::new([]), None, Some(4))),
+            Err(ClientError::QueueFull)
+        ));
+
+        // acking frees up space again.
+        transmit(&mut state, Instant::now()).unwrap();
+        state.ack(0, Instant::now()).unwrap();
+        assert!(state
+            .enqueue(LrdpPacket::create(Box::new([]), None, Some(4)))
+            .is_ok());
+    }
+
+    #[test]
+    fn fragmented_message() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let mut sender = ClientState::new(addr, &LrdpConfig::default());
+        let mut receiver = ClientState::new(addr, &LrdpConfig::default());
+
+        // the message is accepted even though only 4 of its 6 fragments fit in the window.
+        let message: Vec<u8> = (0..11).collect();
+        sender.enqueue_message(&message, 2, None, None).unwrap();
+        assert!(sender.is_full());
+        assert!(matches!(
+            sender.enqueue_message(&[1], 2, None, None),
+            Err(ClientError::QueueFull)
+        ));
+
+        let mut emitted = Vec::new();
+        let mut now = Instant::now();
+        while let Some(packet) = sender.next_packet() {
+            let (seq_num, more_fragments) = (packet.seq_num(), packet.more_fragments());
+            // nothing is emitted until the last fragment arrives.
+            emitted = receiver
+                .recv(seq_num, packet.data(), more_fragments)
+                .unwrap();
+            assert_eq!(emitted.is_empty(), more_fragments);
+            transmit(&mut sender, now).unwrap();
+            sender.ack(seq_num, now).unwrap();
+            now += Duration::from_secs(1);
+        }
+        assert_eq!(emitted, vec![message.into_boxed_slice()]);
+        assert!(!sender.is_full());
+    }
+
     #[test]
     fn ack_good_seq_num() {
         let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
-        let mut state = ClientState::new(addr);
+        let mut state = ClientState::new(addr, &LrdpConfig::default());
         state
             .enqueue(LrdpPacket::create(Box::new([]), None, Some(0)))
             .unwrap();
-        assert!(matches!(state.ack(0), Ok(_)));
+        transmit(&mut state, Instant::now()).unwrap();
+        assert!(state.ack(0, Instant::now()).is_ok());
     }
 
     #[test]
     fn ack_bad_seq_num() {
         let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
-        let mut state = ClientState::new(addr);
+        let mut state = ClientState::new(addr, &LrdpConfig::default());
         state
             .enqueue(LrdpPacket::create(Box::new([]), None, Some(0)))
             .unwrap();
-        assert!(matches!(state.ack(1), Err(ClientError::WrongAck(1))));
+        assert!(matches!(
+            state.ack(1, Instant::now()),
+            Err(ClientError::WrongAck(1))
+        ));
     }
 
     #[test]
     fn ack_mechanism_sequential() {
         let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
-        let mut state = ClientState::new(addr);
+        let mut state = ClientState::new(addr, &LrdpConfig::default());
 
         // enqueue a few packets.
         for i in 0..4 {
@@ -199,19 +1335,20 @@ mod tests {
                 .unwrap();
         }
 
+        transmit(&mut state, Instant::now()).unwrap();
         for i in 0..4 {
             // check that the expected packet is next in the queue.
             let packet = state.next_packet().unwrap();
             assert_eq!(packet.seq_num(), i);
             // ack it so that it is removed from the queue.
-            state.ack(i).unwrap();
+            state.ack(i, Instant::now()).unwrap();
         }
     }
 
     #[test]
     fn ack_mechanism_delayed() {
         let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
-        let mut state = ClientState::new(addr);
+        let mut state = ClientState::new(addr, &LrdpConfig::default());
 
         // enqueue a few packets.
         for i in 0..4 {
@@ -221,10 +1358,674 @@ mod tests {
         }
 
         // ack a whole bunch of the enqueued packets.
-        state.ack(2).unwrap();
+        transmit(&mut state, Instant::now()).unwrap();
+        state.ack(2, Instant::now()).unwrap();
 
         // make sure the expected packet is next.
         let packet = state.next_packet().unwrap();
         assert_eq!(packet.seq_num(), 3);
     }
+
+    #[test]
+    fn recv_out_of_order() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let mut state = ClientState::new(addr, &LrdpConfig::default());
+
+        // receive 1 and 2 before 0, which should be buffered.
+        assert!(state.recv(1, &[1], false).unwrap().is_empty());
+        assert!(state.recv(2, &[2], false).unwrap().is_empty());
+        assert_eq!(state.recv_ack_num(), 7);
+
+        // filling the gap should emit everything in order.
+        let emitted = state.recv(0, &[0], false).unwrap();
+        assert_eq!(
+            emitted,
+            vec![vec![0u8].into(), vec![1u8].into(), vec![2u8].into()]
+        );
+        assert_eq!(state.recv_ack_num(), 2);
+
+        // old and far away sequence numbers are rejected.
+        assert!(matches!(
+            state.recv(1, &[], false),
+            Err(ClientError::WrongSeq(1, 3))
+        ));
+        assert!(matches!(
+            state.recv(7, &[], false),
+            Err(ClientError::WrongSeq(7, 3))
+        ));
+    }
+
+    #[test]
+    fn transmit_window() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let config = LrdpConfig {
+            header_format: HeaderFormat::Extended,
+            max_in_flight: Some(4),
+            ..LrdpConfig::default()
+        };
+        let mut state = ClientState::new(addr, &config);
+        for i in 0..4 {
+            state
+                .enqueue(LrdpPacket::create(Box::new([]), None, Some(i)))
+                .unwrap();
+        }
+
+        // only the packets inside the window are sent.
+        let now = Instant::now();
+        assert_eq!(transmit(&mut state, now).unwrap().len(), 4);
+        assert!(transmit(&mut state, now).unwrap().is_empty());
+
+        // acking some packets moves the window along.
+        state.ack(1, Instant::now()).unwrap();
+        for i in 4..6 {
+            state
+                .enqueue(LrdpPacket::create(Box::new([]), None, Some(i)))
+                .unwrap();
+        }
+        assert_eq!(transmit(&mut state, now).unwrap().len(), 2);
+
+        // nothing is retransmitted until the retransmission timeout has passed.
+        let rto = state.rtt().rto();
+        assert!(transmit(&mut state, now + rto / 2).unwrap().is_empty());
+        assert_eq!(transmit(&mut state, now + rto).unwrap().len(), 4);
+
+        // timing out causes the retransmission timeout to back off.
+        assert_eq!(state.rtt().rto(), rto * 2);
+    }
+
+    #[test]
+    fn ack_samples_rtt() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let mut state = ClientState::new(addr, &LrdpConfig::default());
+        for i in 0..2 {
+            state
+                .enqueue(LrdpPacket::create(Box::new([]), None, Some(i)))
+                .unwrap();
+        }
+        let now = Instant::now();
+        transmit(&mut state, now).unwrap();
+        state.ack(0, now + Duration::from_millis(40)).unwrap();
+        assert_eq!(state.rtt().srtt(), Some(Duration::from_millis(40)));
+
+        // retransmitted packets are not sampled.
+        let rto = state.rtt().rto();
+        transmit(&mut state, now + rto).unwrap();
+        state.ack(1, now + rto * 3).unwrap();
+        assert_eq!(state.rtt().srtt(), Some(Duration::from_millis(40)));
+    }
+
+    #[test]
+    fn duplicate_ack_retransmits_front() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let mut state = ClientState::new(addr, &LrdpConfig::default());
+        for i in 0..3 {
+            state
+                .enqueue(LrdpPacket::create(Box::new([]), None, Some(i)))
+                .unwrap();
+        }
+        let now = Instant::now();
+        transmit(&mut state, now).unwrap();
+        state.ack(0, Instant::now()).unwrap();
+
+        // the receiver got 2 but not 1, so it acks 0 again.
+        assert!(matches!(
+            state.ack(0, Instant::now()),
+            Err(ClientError::DuplicateAck(0))
+        ));
+        let buffers = transmit(&mut state, now).unwrap();
+        assert_eq!(buffers, vec![vec![0b10001000]]);
+
+        // a second duplicate does not cause another retransmission.
+        assert!(matches!(
+            state.ack(0, Instant::now()),
+            Err(ClientError::DuplicateAck(0))
+        ));
+        assert!(transmit(&mut state, now).unwrap().is_empty());
+    }
+
+    #[test]
+    fn ack_exhausted() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let mut state = ClientState::new(addr, &LrdpConfig::default());
+        state
+            .enqueue(LrdpPacket::create(Box::new([]), None, Some(0)))
+            .unwrap();
+        transmit(&mut state, Instant::now()).unwrap();
+        state.ack(0, Instant::now()).unwrap();
+        assert!(matches!(
+            state.ack(0, Instant::now()),
+            Err(ClientError::Exhausted)
+        ));
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let config = LrdpConfig::default();
+        let now = Instant::now();
+        let deadline = now + config.handshake_timeout;
+
+        // a SYN which is never acknowledged.
+        let mut opening = ClientState::new(addr, &config);
+        opening.open();
+        transmit(&mut opening, now).unwrap();
+        assert!(opening.poll_timeout().unwrap() <= deadline);
+        assert!(opening
+            .handle_timeout(deadline - Duration::from_millis(1))
+            .is_ok());
+        assert!(matches!(
+            opening.handle_timeout(deadline),
+            Err(ClientError::Unreachable)
+        ));
+        assert_eq!(opening.state(), ConnectionState::Unreachable);
+
+        // a FIN which is never acknowledged.
+        let mut closing = ClientState::new(addr, &config);
+        closing.close();
+        transmit(&mut closing, now).unwrap();
+        assert_eq!(closing.state(), ConnectionState::Closing);
+        assert!(matches!(
+            closing.handle_timeout(deadline),
+            Err(ClientError::Unreachable)
+        ));
+        assert_eq!(closing.state(), ConnectionState::Unreachable);
+    }
+
+    #[test]
+    fn sequence_numbers_are_reused_once_drained() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let mut state = ClientState::new(addr, &LrdpConfig::default());
+        let now = Instant::now();
+        for i in 0..4 {
+            state
+                .enqueue(LrdpPacket::create(Box::new([]), None, Some(i)))
+                .unwrap();
+        }
+        transmit(&mut state, now).unwrap();
+        state.ack(3, now).unwrap();
+
+        // the next lap of the sequence space waits for acks from the last one to drain.
+        let reuse_at = now + state.rtt().rto();
+        for i in 4..8 {
+            state
+                .enqueue(LrdpPacket::create(Box::new([]), None, Some(i)))
+                .unwrap();
+        }
+        assert!(transmit(&mut state, now).unwrap().is_empty());
+        assert_eq!(state.poll_timeout(), Some(reuse_at));
+        // a packet which has not been sent cannot have been received, so this ack is stale.
+        assert!(matches!(state.ack(5, now), Err(ClientError::WrongAck(5))));
+        assert_eq!(transmit(&mut state, reuse_at).unwrap().len(), 4);
+        assert!(state.ack(5, reuse_at).is_ok());
+    }
+
+    #[test]
+    fn extended_sequence_space() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let config = LrdpConfig {
+            header_format: HeaderFormat::Extended,
+            ..LrdpConfig::default()
+        };
+        let mut state = ClientState::new(addr, &config);
+
+        // sequence numbers no longer wrap around after 8 packets.
+        for i in 0..10 {
+            state
+                .enqueue(LrdpPacket::create(Box::new([]), None, Some(i)))
+                .unwrap();
+        }
+        assert_eq!(state.next_seq_num(), 10);
+        assert_eq!(transmit(&mut state, Instant::now()).unwrap().len(), 10);
+        state.ack(9, Instant::now()).unwrap();
+        assert!(state.next_packet().is_none());
+
+        // the receive window is larger too.
+        assert!(state.recv(9, &[], false).unwrap().is_empty());
+        assert_eq!(state.recv_ack_num(), u16::MAX);
+    }
+
+    #[test]
+    fn handshake() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let mut state = ClientState::new(addr, &LrdpConfig::default());
+        state.open();
+        state
+            .enqueue(LrdpPacket::create(Box::new([]), None, Some(0)))
+            .unwrap();
+
+        // only the SYN is sent while the connection is opening.
+        let now = Instant::now();
+        assert_eq!(
+            transmit(&mut state, now).unwrap(),
+            vec![vec![0b00000001, 0]]
+        );
+        assert_eq!(state.recv_control(ControlType::SynAck, now), None);
+        assert_eq!(state.state(), ConnectionState::Open);
+        assert_eq!(transmit(&mut state, now).unwrap(), vec![vec![0b10000000]]);
+    }
+
+    #[test]
+    fn close_after_data_is_acked() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let mut state = ClientState::new(addr, &LrdpConfig::default());
+        state
+            .enqueue(LrdpPacket::create(Box::new([]), None, Some(0)))
+            .unwrap();
+        state.close();
+
+        // the FIN is held back until the data is acknowledged.
+        let now = Instant::now();
+        assert_eq!(transmit(&mut state, now).unwrap().len(), 1);
+        assert_eq!(state.state(), ConnectionState::Open);
+        state.ack(0, now).unwrap();
+        assert_eq!(transmit(&mut state, now).unwrap(), vec![vec![0b00000011]]);
+        assert_eq!(state.state(), ConnectionState::Closing);
+
+        assert_eq!(state.recv_control(ControlType::FinAck, now), None);
+        assert_eq!(state.state(), ConnectionState::Closed);
+    }
+
+    #[test]
+    fn syn_resets_used_state() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let mut state = ClientState::new(addr, &LrdpConfig::default());
+        state.recv(0, &[], false).unwrap();
+        assert_eq!(
+            state.recv_control(ControlType::Syn, Instant::now()),
+            Some(ControlType::SynAck)
+        );
+        assert_eq!(state.recv_ack_num(), 7);
+    }
+
+    #[test]
+    fn retransmitted_syn_keeps_state() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let config = LrdpConfig::default();
+        let mut state = ClientState::new(addr, &config);
+        let now = Instant::now();
+        state.recv_control(ControlType::Syn, now);
+        state.recv(0, &[], false).unwrap();
+
+        // a copy of the SYN which arrives after the data does not start a new connection.
+        let later = now + Duration::from_secs(1);
+        state.recv_control(ControlType::Syn, later);
+        assert_eq!(state.recv_ack_num(), 0);
+
+        // but a SYN which comes after the client would have given up on the first one does.
+        let reopened = now + config.handshake_timeout;
+        state.recv_control(ControlType::Syn, reopened);
+        assert_eq!(state.recv_ack_num(), 7);
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let config = LrdpConfig {
+            idle_timeout: Some(Duration::from_secs(5)),
+            ..LrdpConfig::default()
+        };
+        let mut state = ClientState::new(addr, &config);
+        let now = Instant::now();
+        assert!(!state.is_idle(now));
+
+        state.last_recv = Some(now);
+        assert!(!state.is_idle(now + Duration::from_secs(4)));
+        assert!(state.is_idle(now + Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn keepalive() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let config = LrdpConfig {
+            keepalive_interval: Some(Duration::from_secs(1)),
+            ..LrdpConfig::default()
+        };
+        let mut state = ClientState::new(addr, &config);
+        state
+            .enqueue(LrdpPacket::create(Box::new([]), None, Some(0)))
+            .unwrap();
+        let now = Instant::now();
+        transmit(&mut state, now).unwrap();
+        state.ack(0, now).unwrap();
+
+        assert!(transmit(&mut state, now).unwrap().is_empty());
+        let later = now + Duration::from_secs(1);
+        assert_eq!(transmit(&mut state, later).unwrap(), vec![vec![0b00000101]]);
+        assert!(transmit(&mut state, later).unwrap().is_empty());
+    }
+
+    #[test]
+    fn poll_timeout() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let config = LrdpConfig {
+            keepalive_interval: Some(Duration::from_secs(1)),
+            idle_timeout: Some(Duration::from_secs(2)),
+            ..LrdpConfig::default()
+        };
+        let mut state = ClientState::new(addr, &config);
+        assert_eq!(state.poll_timeout(), None);
+
+        // a packet in flight has to be retransmitted after the retransmission timeout.
+        state
+            .enqueue(LrdpPacket::create(Box::new([]), None, Some(0)))
+            .unwrap();
+        let now = Instant::now();
+        transmit(&mut state, now).unwrap();
+        assert_eq!(state.poll_timeout(), Some(now + state.rtt().rto()));
+
+        // once it is acked, the next thing to do is send a keepalive.
+        state.ack(0, now).unwrap();
+        assert_eq!(state.poll_timeout(), Some(now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn delayed_ack() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let mut state = ClientState::new(addr, &LrdpConfig::default());
+        let now = Instant::now();
+        state.recv(0, &[1], false).unwrap();
+
+        // the ack waits for data to piggyback on, until the ack delay runs out.
+        assert!(transmit(&mut state, now).unwrap().is_empty());
+        let due = now + LrdpConfig::default().ack_delay.unwrap();
+        assert_eq!(state.poll_timeout(), Some(due));
+        assert_eq!(transmit(&mut state, due).unwrap(), vec![vec![0b01000000]]);
+        assert_eq!(state.poll_timeout(), None);
+
+        // a second packet is acked straight away.
+        state.recv(1, &[2], false).unwrap();
+        assert!(transmit(&mut state, due).unwrap().is_empty());
+        state.recv(2, &[3], false).unwrap();
+        assert_eq!(transmit(&mut state, due).unwrap(), vec![vec![0b01000010]]);
+    }
+
+    #[test]
+    fn piggybacked_ack() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let mut state = ClientState::new(addr, &LrdpConfig::default());
+        let now = Instant::now();
+        state.recv(0, &[1], false).unwrap();
+        state.enqueue_message(&[9], 16, None, None).unwrap();
+
+        // the ack goes out with the data, so nothing else needs to be sent later.
+        assert_eq!(
+            transmit(&mut state, now).unwrap(),
+            vec![vec![0b11000000, 9]]
+        );
+        assert_eq!(state.poll_timeout(), Some(now + state.rtt().rto()));
+
+        // repeated piggybacked acks are not duplicate acks.
+        state
+            .ack_piggybacked(prev_seq(HeaderFormat::Compact, 0), now)
+            .unwrap();
+        assert!(transmit(&mut state, now).unwrap().is_empty());
+    }
+
+    #[test]
+    fn out_of_order_acks_immediately() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let mut state = ClientState::new(addr, &LrdpConfig::default());
+        let now = Instant::now();
+        state.recv(1, &[2], false).unwrap();
+        assert_eq!(transmit(&mut state, now).unwrap(), vec![vec![0b01000111]]);
+    }
+
+    #[test]
+    fn selective_ack_skips_received_packets() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let config = LrdpConfig {
+            selective_ack: true,
+            ..LrdpConfig::default()
+        };
+        let mut state = ClientState::new(addr, &config);
+        state.negotiate(SELECTIVE_ACK_OPTION);
+        for i in 0..4 {
+            state
+                .enqueue(LrdpPacket::create(Box::new([]), None, Some(i)))
+                .unwrap();
+        }
+        let now = Instant::now();
+        assert_eq!(transmit(&mut state, now).unwrap().len(), 4);
+
+        // 1 and 2 arrived, so 0 must have been lost and is retransmitted straight away.
+        assert!(matches!(
+            state.selective_ack(7, &[0b00000011], now),
+            Err(ClientError::DuplicateAck(7))
+        ));
+        assert_eq!(transmit(&mut state, now).unwrap(), vec![vec![0b10000000]]);
+
+        // after a timeout only the packets which have not been selectively acked are resent.
+        let later = now + state.rtt().rto();
+        assert_eq!(
+            transmit(&mut state, later).unwrap(),
+            vec![vec![0b10000000], vec![0b10011000]]
+        );
+    }
+
+    #[test]
+    fn selective_ack_bitmap() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let config = LrdpConfig {
+            selective_ack: true,
+            ..LrdpConfig::default()
+        };
+        let mut state = ClientState::new(addr, &config);
+        state.recv(1, &[], false).unwrap();
+        state.recv(3, &[], false).unwrap();
+        let now = Instant::now();
+
+        // nothing but the header is sent until selective acks have been negotiated.
+        assert_eq!(transmit(&mut state, now).unwrap(), vec![vec![0b01000111]]);
+        state.negotiate(SELECTIVE_ACK_OPTION);
+        state.recv(2, &[], false).unwrap();
+        assert_eq!(
+            transmit(&mut state, now).unwrap(),
+            vec![vec![0b01000111, 0b00000111]]
+        );
+    }
+
+    #[test]
+    fn stats_attribute_traffic() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let mut state = ClientState::new(addr, &LrdpConfig::default());
+        state.enqueue_message(&[1, 2, 3], 100, None, None).unwrap();
+        state.enqueue_message(&[4, 5], 100, None, None).unwrap();
+        let now = Instant::now();
+        transmit(&mut state, now).unwrap();
+        let later = now + state.rtt().rto();
+        transmit(&mut state, later).unwrap();
+
+        // a wrong ack and a duplicate one.
+        assert!(state.ack(5, later).is_err());
+        assert!(state.ack(7, later).is_err());
+        state.ack(0, later).unwrap();
+
+        // one packet is out of order, and another was already received.
+        state.recv(1, &[6], false).unwrap();
+        state.recv(1, &[6], false).unwrap();
+        state.recv(0, &[7, 8], false).unwrap();
+        assert!(state.recv(1, &[6], false).is_err());
+
+        let stats = state.stats();
+        assert_eq!(stats.data_packets_sent, 2);
+        assert_eq!(stats.payload_bytes_sent, 5);
+        assert_eq!(stats.retransmissions, 2);
+        assert_eq!(stats.acks_received, 3);
+        assert_eq!(stats.wrong_acks, 1);
+        assert_eq!(stats.duplicate_acks, 1);
+        assert_eq!(stats.out_of_order_packets, 1);
+        assert_eq!(stats.duplicate_packets, 2);
+        assert_eq!(stats.wrong_seqs, 1);
+        assert_eq!(stats.payload_bytes_received, 3);
+        assert_eq!(stats.queue_depth, 1);
+        assert_eq!(stats.srtt, None);
+
+        // the received data is acknowledged on the next data packet.
+        state.enqueue_message(&[9], 100, None, None).unwrap();
+        transmit(&mut state, later).unwrap();
+        let stats = state.stats();
+        assert_eq!(stats.piggybacked_acks, 1);
+        assert_eq!(stats.acks_sent, 0);
+    }
+
+    #[test]
+    fn take_undelivered_messages() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let mut state = ClientState::new(addr, &LrdpConfig::default());
+        state.enqueue_message(&[1, 2], 100, None, None).unwrap();
+        state.enqueue_message(&[], 100, None, None).unwrap();
+        state
+            .enqueue_message(&[3, 4, 5, 6, 7], 1, None, None)
+            .unwrap();
+        transmit(&mut state, Instant::now()).unwrap();
+        state.ack(0, Instant::now()).unwrap();
+
+        // the fragments which did not fit in the send queue are put back together too.
+        assert!(state.has_queued_data());
+        assert_eq!(
+            state.take_undelivered(),
+            vec![Box::from([]), Box::from([3, 4, 5, 6, 7])]
+        );
+        assert!(!state.has_queued_data());
+    }
+
+    #[test]
+    fn message_deliveries() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let mut state = ClientState::new(addr, &LrdpConfig::default());
+        let first = state.enqueue_message(&[1, 2, 3], 1, None, None).unwrap();
+        let second = state.enqueue_message(&[4], 1, None, None).unwrap();
+        assert_ne!(first, second);
+
+        // a message is only delivered once its last fragment has been acknowledged.
+        let now = Instant::now();
+        transmit(&mut state, now).unwrap();
+        state.ack(1, now + Duration::from_millis(50)).unwrap();
+        assert!(state.take_deliveries().is_empty());
+        assert!(state.is_pending(first));
+        state.ack(3, now + Duration::from_millis(80)).unwrap();
+        let latency = Delivery::Acknowledged(Duration::from_millis(80));
+        assert_eq!(
+            state.take_deliveries(),
+            vec![(first, latency), (second, latency)]
+        );
+        assert!(!state.is_pending(first));
+
+        let third = state.enqueue_message(&[5], 1, None, None).unwrap();
+        state.take_undelivered();
+        assert_eq!(state.take_deliveries(), vec![(third, Delivery::Lost)]);
+    }
+
+    #[test]
+    fn expired_message_is_skipped() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let mut sender = ClientState::new(addr, &LrdpConfig::default());
+        let mut receiver = ClientState::new(addr, &LrdpConfig::default());
+        let now = Instant::now();
+        let deadline = now + Duration::from_millis(10);
+        let first = sender.enqueue_message(&[1], 16, None, None).unwrap();
+        let second = sender
+            .enqueue_message(&[2], 16, Some(deadline), None)
+            .unwrap();
+        let third = sender.enqueue_message(&[3], 16, None, None).unwrap();
+        assert_eq!(transmit(&mut sender, now).unwrap().len(), 3);
+
+        // the second message is lost, so the third one waits behind it.
+        assert_eq!(receiver.recv(0, &[1], false).unwrap(), vec![Box::from([1])]);
+        assert!(receiver.recv(2, &[3], false).unwrap().is_empty());
+        sender.ack(0, now).unwrap();
+        assert_eq!(sender.poll_timeout(), Some(deadline));
+
+        // once it expires, the receiver is told to skip it instead of it being retransmitted.
+        let buffers = transmit(&mut sender, deadline).unwrap();
+        assert_eq!(buffers.len(), 1);
+        let forward = LrdpPacket::parse(&buffers[0], HeaderFormat::Compact).unwrap();
+        assert_eq!(forward.forward_seq(), Some(2));
+        assert_eq!(receiver.skip_to(2), vec![Box::from([3])]);
+        assert!(receiver.skip_to(2).is_empty());
+        assert_eq!(receiver.recv_ack_num(), 2);
+
+        sender.ack(2, deadline).unwrap();
+        assert_eq!(
+            sender.take_deliveries(),
+            vec![
+                (first, Delivery::Acknowledged(Duration::from_millis(0))),
+                (second, Delivery::Expired),
+                (third, Delivery::Acknowledged(Duration::from_millis(10)))
+            ]
+        );
+        assert!(!sender.has_queued_data());
+        assert!(transmit(&mut sender, deadline).unwrap().is_empty());
+    }
+
+    #[test]
+    fn superseded_message_is_skipped() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let mut sender = ClientState::new(addr, &LrdpConfig::default());
+        let mut receiver = ClientState::new(addr, &LrdpConfig::default());
+        let old = sender.enqueue_message(&[1], 16, None, Some(7)).unwrap();
+        let other = sender.enqueue_message(&[2], 16, None, Some(8)).unwrap();
+        let new = sender.enqueue_message(&[3], 16, None, Some(7)).unwrap();
+        assert_eq!(sender.take_deliveries(), vec![(old, Delivery::Superseded)]);
+
+        // the old message is never sent, and the receiver is told to skip it instead.
+        let now = Instant::now();
+        let buffers = transmit(&mut sender, now).unwrap();
+        assert_eq!(buffers.len(), 3);
+        let forward = LrdpPacket::parse(&buffers[0], HeaderFormat::Compact).unwrap();
+        assert_eq!(forward.forward_seq(), Some(1));
+        assert!(receiver.skip_to(1).is_empty());
+        assert_eq!(receiver.recv(1, &[2], false).unwrap(), vec![Box::from([2])]);
+        assert_eq!(receiver.recv(2, &[3], false).unwrap(), vec![Box::from([3])]);
+
+        sender.ack(2, now).unwrap();
+        let acked = Delivery::Acknowledged(Duration::from_millis(0));
+        assert_eq!(sender.take_deliveries(), vec![(other, acked), (new, acked)]);
+        assert!(sender.take_undelivered().is_empty());
+    }
+
+    #[test]
+    fn unreachable_after_max_retransmissions() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let config = LrdpConfig {
+            max_retransmissions: Some(2),
+            ..LrdpConfig::default()
+        };
+        let mut state = ClientState::new(addr, &config);
+        state
+            .enqueue(LrdpPacket::create(Box::new([]), None, Some(0)))
+            .unwrap();
+
+        let mut now = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(transmit(&mut state, now).unwrap().len(), 1);
+            now += state.rtt().rto();
+        }
+        assert!(matches!(
+            transmit(&mut state, now),
+            Err(ClientError::Unreachable)
+        ));
+        assert_eq!(state.state(), ConnectionState::Unreachable);
+        assert!(state.next_packet().is_none());
+        assert!(transmit(&mut state, now).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unreachable_after_max_delivery_time() {
+        let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+        let config = LrdpConfig {
+            max_retransmissions: None,
+            max_delivery_time: Some(Duration::from_secs(1)),
+            ..LrdpConfig::default()
+        };
+        let mut state = ClientState::new(addr, &config);
+        state.open();
+
+        let now = Instant::now();
+        transmit(&mut state, now).unwrap();
+        assert!(matches!(
+            transmit(&mut state, now + Duration::from_secs(1)),
+            Err(ClientError::Unreachable)
+        ));
+    }
 }
@@ -0,0 +1,58 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+
+/// Something which an LRDP socket can send and receive datagrams over. This is a UDP socket
+/// normally, but it can be swapped out for something else, such as a simulated network in tests.
+pub trait DatagramTransport: Send + Sync + 'static {
+    /// Sends `buf` as a single datagram to `addr`, and returns the number of bytes sent.
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+
+    /// Receives a single datagram into `buf`, and returns its length along with the address it
+    /// came from. This blocks until a datagram arrives or the transport is woken up.
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    /// Wakes up a call to `recv_from` which is waiting for a datagram, or the next one if nothing
+    /// is waiting, by making it receive an empty datagram. The socket uses this to stop reading
+    /// when it stops.
+    fn wake(&self) -> io::Result<()>;
+
+    /// Returns the address that this transport is bound to.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// Whether an error from receiving a datagram was caused by the network rather than the transport,
+/// so that receiving can carry on. Some platforms report an ICMP port unreachable for an earlier
+/// send this way.
+pub(crate) fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionRefused
+    )
+}
+
+impl DatagramTransport for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    /// Sends an empty datagram to the socket itself.
+    fn wake(&self) -> io::Result<()> {
+        let mut addr = UdpSocket::local_addr(self)?;
+        // a socket which is bound to every interface can be reached over loopback.
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        UdpSocket::send_to(self, &[], addr).map(|_| ())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
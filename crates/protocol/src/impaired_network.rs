@@ -0,0 +1,666 @@
+use crate::datagram_transport::DatagramTransport;
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// How a simulated network treats the datagrams which are sent over it. Apart from `PERFECT`, the
+/// named profiles match the five network conditions which `tools/do-run.sh` sets up with
+/// `tc netem`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NetworkProfile {
+    /// How long it takes for a datagram to arrive.
+    pub delay: Duration,
+    /// How far the delay of each datagram can be off from `delay`, in either direction. Datagrams
+    /// which are sent close together can be reordered by this.
+    pub jitter: Duration,
+    /// The probability of a datagram being lost, from 0 to 1.
+    pub loss: f64,
+    /// The probability of a datagram skipping the delay entirely and overtaking the datagrams
+    /// which are already on their way, from 0 to 1.
+    pub reorder: f64,
+    /// The probability of a datagram arriving twice, from 0 to 1.
+    pub duplicate: f64,
+}
+
+impl NetworkProfile {
+    /// A network which delivers everything straight away. This is the one profile which
+    /// `tools/do-run.sh` does not have.
+    pub const PERFECT: NetworkProfile = NetworkProfile {
+        delay: Duration::from_millis(0),
+        jitter: Duration::from_millis(0),
+        loss: 0.0,
+        reorder: 0.0,
+        duplicate: 0.0,
+    };
+    /// 20ms ± 10ms of delay, with 0.5% loss.
+    pub const NORMAL: NetworkProfile = NetworkProfile {
+        delay: Duration::from_millis(20),
+        jitter: Duration::from_millis(10),
+        loss: 0.005,
+        ..NetworkProfile::PERFECT
+    };
+    /// 40ms ± 20ms of delay, with 2% loss.
+    pub const ACCEPTABLE: NetworkProfile = NetworkProfile {
+        delay: Duration::from_millis(40),
+        jitter: Duration::from_millis(20),
+        loss: 0.02,
+        ..NetworkProfile::PERFECT
+    };
+    /// 80ms ± 20ms of delay, with 6% loss.
+    pub const DEGRADED: NetworkProfile = NetworkProfile {
+        delay: Duration::from_millis(80),
+        jitter: Duration::from_millis(20),
+        loss: 0.06,
+        ..NetworkProfile::PERFECT
+    };
+    /// 150ms ± 30ms of delay, with 12% loss.
+    pub const HORRIBLE: NetworkProfile = NetworkProfile {
+        delay: Duration::from_millis(150),
+        jitter: Duration::from_millis(30),
+        loss: 0.12,
+        ..NetworkProfile::PERFECT
+    };
+    /// 40ms ± 20ms of delay, with 20% loss, for stress testing the reliability features.
+    pub const STRESS: NetworkProfile = NetworkProfile {
+        delay: Duration::from_millis(40),
+        jitter: Duration::from_millis(20),
+        loss: 0.2,
+        ..NetworkProfile::PERFECT
+    };
+}
+
+/// A small pseudo random number generator (SplitMix64), so that a simulated network behaves the
+/// same way every time it is given the same seed.
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number between 0 and 1.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns whether something with the given `probability` happens.
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+}
+
+/// A datagram which is on its way to an address.
+#[derive(Debug)]
+struct InFlight {
+    deliver_at: Instant,
+    /// The order in which datagrams were sent, so that datagrams which are due at the same time
+    /// arrive in the order they were sent in.
+    order: u64,
+    from: SocketAddr,
+    data: Vec<u8>,
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for InFlight {}
+
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InFlight {
+    /// The datagram which is due first is the greatest, so that it is at the top of a heap.
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.deliver_at, other.order).cmp(&(self.deliver_at, self.order))
+    }
+}
+
+#[derive(Debug)]
+struct Network {
+    profile: NetworkProfile,
+    rng: Rng,
+    /// The number of datagrams which have been sent.
+    sent: u64,
+    /// The port which the next bound transport gets.
+    next_port: u16,
+    /// The datagrams which are on their way to each bound address.
+    inboxes: HashMap<SocketAddr, BinaryHeap<InFlight>>,
+}
+
+impl Network {
+    /// Puts `data` on its way to `to`, unless it is lost. Datagrams for addresses which are not
+    /// bound are dropped.
+    fn send(&mut self, from: SocketAddr, to: SocketAddr, data: &[u8], now: Instant) {
+        if !self.inboxes.contains_key(&to) || self.rng.chance(self.profile.loss) {
+            return;
+        }
+        let copies = if self.rng.chance(self.profile.duplicate) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let delay = if self.rng.chance(self.profile.reorder) {
+                Duration::from_millis(0)
+            } else {
+                // the delay is spread evenly across delay ± jitter.
+                let jitter = self.profile.jitter.as_secs_f64();
+                let offset = (self.rng.next_f64() * 2.0 - 1.0) * jitter;
+                Duration::from_secs_f64((self.profile.delay.as_secs_f64() + offset).max(0.0))
+            };
+            self.sent += 1;
+            let datagram = InFlight {
+                deliver_at: now + delay,
+                order: self.sent,
+                from,
+                data: data.to_vec(),
+            };
+            if let Some(inbox) = self.inboxes.get_mut(&to) {
+                inbox.push(datagram);
+            }
+        }
+    }
+
+    /// Returns the next datagram for `addr` which has arrived by `now`.
+    fn recv(&mut self, addr: SocketAddr, now: Instant) -> Option<(Vec<u8>, SocketAddr)> {
+        let inbox = self.inboxes.get_mut(&addr)?;
+        if inbox.peek()?.deliver_at > now {
+            return None;
+        }
+        inbox.pop().map(|datagram| (datagram.data, datagram.from))
+    }
+
+    /// Returns when the next datagram for `addr` arrives.
+    fn next_arrival(&self, addr: SocketAddr) -> Option<Instant> {
+        Some(self.inboxes.get(&addr)?.peek()?.deliver_at)
+    }
+}
+
+/// A simulated network which lives in memory, and which loses, delays, reorders and duplicates
+/// datagrams according to a `NetworkProfile`. All of its decisions come from a seeded random
+/// number generator, so the same datagrams sent at the same times are always treated the same.
+///
+/// Transports which are bound to the network can be used by an `LrdpSocket` in place of a UDP
+/// socket. The network can also be driven directly with explicit times through `send` and `recv`,
+/// which makes simulations independent of the wall clock.
+#[derive(Debug, Clone)]
+pub struct ImpairedNetwork {
+    shared: Arc<(Mutex<Network>, Condvar)>,
+}
+
+impl ImpairedNetwork {
+    /// Creates an empty network which behaves according to `profile`, making its random decisions
+    /// from `seed`.
+    pub fn new(profile: NetworkProfile, seed: u64) -> Self {
+        let network = Network {
+            profile,
+            rng: Rng(seed),
+            sent: 0,
+            next_port: 1,
+            inboxes: HashMap::new(),
+        };
+        Self {
+            shared: Arc::new((Mutex::new(network), Condvar::new())),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Network> {
+        // the network is never left in an inconsistent state, so a poisoned lock is fine to use.
+        self.shared.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Binds a new transport to the network, on an address which has not been used yet.
+    pub fn bind(&self) -> ImpairedTransport {
+        let addr = self.bind_addr();
+        ImpairedTransport {
+            network: self.clone(),
+            addr,
+            woken: AtomicBool::new(false),
+        }
+    }
+
+    /// Reserves a new address on the network which datagrams can be sent to, for driving the
+    /// network directly.
+    pub fn bind_addr(&self) -> SocketAddr {
+        let mut network = self.lock();
+        let port = network.next_port;
+        network.next_port += 1;
+        let addr = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), port));
+        network.inboxes.insert(addr, BinaryHeap::new());
+        addr
+    }
+
+    /// Sends `data` from `from` to `to` at `now`.
+    pub fn send(&self, from: SocketAddr, to: SocketAddr, data: &[u8], now: Instant) {
+        self.lock().send(from, to, data, now);
+        self.shared.1.notify_all();
+    }
+
+    /// Returns the next datagram for `addr` which has arrived by `now`, along with the address it
+    /// came from.
+    pub fn recv(&self, addr: SocketAddr, now: Instant) -> Option<(Vec<u8>, SocketAddr)> {
+        self.lock().recv(addr, now)
+    }
+
+    /// Returns when the next datagram which is on its way to `addr` arrives, or `None` if there is
+    /// nothing on its way.
+    pub fn next_arrival(&self, addr: SocketAddr) -> Option<Instant> {
+        self.lock().next_arrival(addr)
+    }
+}
+
+/// A transport which is bound to an `ImpairedNetwork`, and sends and receives datagrams in real
+/// time.
+#[derive(Debug)]
+pub struct ImpairedTransport {
+    network: ImpairedNetwork,
+    addr: SocketAddr,
+    /// Set when the transport is woken up, until `recv_from` returns because of it.
+    woken: AtomicBool,
+}
+
+impl DatagramTransport for ImpairedTransport {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.network.send(self.addr, addr, buf, Instant::now());
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (_, arrived) = &*self.network.shared;
+        let mut network = self.network.lock();
+        loop {
+            if self.woken.swap(false, atomic::Ordering::Relaxed) {
+                return Ok((0, self.addr));
+            }
+            let now = Instant::now();
+            if let Some((data, from)) = network.recv(self.addr, now) {
+                // like UDP, anything which does not fit in the buffer is cut off.
+                let len = data.len().min(buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                return Ok((len, from));
+            }
+            // sleep until the next datagram arrives, something new is sent, or this is woken up.
+            network = match network.next_arrival(self.addr) {
+                Some(arrival) => {
+                    let wait = arrival.saturating_duration_since(now);
+                    arrived
+                        .wait_timeout(network, wait)
+                        .map(|(network, _)| network)
+                        .unwrap_or_else(|e| e.into_inner().0)
+                }
+                None => arrived.wait(network).unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+    }
+
+    /// Makes `recv_from` return an empty datagram from the transport itself.
+    fn wake(&self) -> io::Result<()> {
+        // the flag is set while holding the network, so that a receiver which is about to wait
+        // cannot miss it.
+        let _network = self.network.lock();
+        self.woken.store(true, atomic::Ordering::Relaxed);
+        self.network.shared.1.notify_all();
+        Ok(())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for ImpairedTransport {
+    /// Unbinds the transport, so that anything sent to its address from now on is dropped.
+    fn drop(&mut self) {
+        self.network.lock().inboxes.remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lrdp_config::LrdpConfig;
+    use crate::lrdp_delivery::SendOptions;
+    use crate::lrdp_packet::HeaderFormat;
+    use crate::lrdp_stats::LrdpStats;
+    use crate::socket_core::{Output, SocketCore};
+    use crate::timer_queue::TimerQueue;
+
+    /// A socket core which is driven by a simulation rather than by threads.
+    struct Peer {
+        addr: SocketAddr,
+        core: SocketCore,
+        timers: TimerQueue<SocketAddr>,
+        emitted: Vec<Vec<u8>>,
+    }
+
+    impl Peer {
+        fn new(network: &ImpairedNetwork, config: LrdpConfig) -> Self {
+            let addr = network.bind_addr();
+            Self {
+                addr,
+                core: SocketCore::new(addr, config),
+                timers: TimerQueue::new(),
+                emitted: Vec::new(),
+            }
+        }
+
+        /// Acts on the output of the core at `now`, like a socket's threads would.
+        fn apply(&mut self, mut out: Output, network: &ImpairedNetwork, now: Instant) {
+            let addr = self.addr;
+            let sent = self.core.transmit(now, &mut out, |buf, to| {
+                network.send(addr, to, buf, now);
+                Ok(buf.len())
+            });
+            sent.unwrap();
+            for (data, _) in out.emitted {
+                self.emitted.push(data);
+            }
+            for (addr, deadline) in out.timers {
+                self.timers.schedule(addr, deadline);
+            }
+        }
+
+        /// Returns when the peer next needs to do something.
+        fn next_event(&mut self, network: &ImpairedNetwork) -> Option<Instant> {
+            let arrival = network.next_arrival(self.addr);
+            let deadline = self.timers.next_deadline();
+            arrival.into_iter().chain(deadline).min()
+        }
+
+        /// Handles everything which is due at `now`.
+        fn step(&mut self, network: &ImpairedNetwork, now: Instant) {
+            let mut out = Output::default();
+            while let Some((buf, from)) = network.recv(self.addr, now) {
+                self.core.handle_datagram(&buf, from, now, &mut out);
+            }
+            while let Some(addr) = self.timers.pop_expired(now) {
+                self.core.handle_timeout(addr, now);
+            }
+            self.apply(out, network, now);
+        }
+    }
+
+    /// Sends `count` messages from one peer to another over a network with the given `profile`,
+    /// one every `interval` or whenever there is room in the send queue after that, then closes the
+    /// connection, all in simulated time. Returns the statistics of the sender and the receiver
+    /// from just before the connection was closed.
+    fn simulate(
+        profile: NetworkProfile,
+        format: HeaderFormat,
+        interval: Duration,
+        seed: u64,
+        count: u8,
+    ) -> (LrdpStats, LrdpStats) {
+        let options = SendOptions::default();
+        let (sender, receiver, emitted) =
+            simulate_with(profile, format, interval, seed, count, options);
+
+        // every message arrives exactly once and in order.
+        let expected: Vec<Vec<u8>> = (0..count).map(|i| vec![i]).collect();
+        assert_eq!(emitted, expected);
+        (sender, receiver)
+    }
+
+    /// Like `simulate`, but sends every message with the given `options`, so that not all of them
+    /// have to arrive. Returns the messages which the receiver emitted as well.
+    fn simulate_with(
+        profile: NetworkProfile,
+        format: HeaderFormat,
+        interval: Duration,
+        seed: u64,
+        count: u8,
+        options: SendOptions,
+    ) -> (LrdpStats, LrdpStats, Vec<Vec<u8>>) {
+        let network = ImpairedNetwork::new(profile, seed);
+        let config = LrdpConfig {
+            header_format: format,
+            max_retransmissions: None,
+            ..LrdpConfig::default()
+        };
+        let mut sender = Peer::new(&network, config);
+        let mut receiver = Peer::new(&network, config);
+        let receiver_addr = receiver.addr;
+        let sender_addr = sender.addr;
+
+        let start = Instant::now();
+        let mut now = start;
+        let mut next_message = 0;
+        let mut next_send = now;
+        let mut stats = None;
+        loop {
+            // queue the next messages if it is time and there is room for them.
+            while next_message < count && now >= next_send && !sender.core.is_full(receiver_addr) {
+                sender
+                    .core
+                    .send(receiver_addr, &[next_message], options, now)
+                    .unwrap();
+                next_message += 1;
+                next_send += interval;
+            }
+            // close the connection once everything has been received, or has been acknowledged or
+            // given up on if messages can expire.
+            let done = match options.ttl {
+                None => receiver.emitted.len() == count as usize,
+                Some(_) => next_message == count && !sender.core.has_queued_data(),
+            };
+            if stats.is_none() && done {
+                let sender_stats = sender.core.peer_stats(receiver_addr).unwrap();
+                let receiver_stats = receiver.core.peer_stats(sender_addr).unwrap();
+                stats = Some((sender_stats, receiver_stats));
+                sender.core.close(receiver_addr);
+            }
+            sender.apply(Output::default(), &network, now);
+
+            let send_due = if next_message < count && !sender.core.is_full(receiver_addr) {
+                Some(next_send)
+            } else {
+                None
+            };
+            now = match [
+                sender.next_event(&network),
+                receiver.next_event(&network),
+                send_due,
+            ]
+            .iter()
+            .flatten()
+            .min()
+            {
+                Some(&next) => next,
+                None => break,
+            };
+            sender.step(&network, now);
+            receiver.step(&network, now);
+            assert!(
+                now - start < Duration::from_secs(600),
+                "the transfer got stuck"
+            );
+        }
+
+        // the connection closes on both sides.
+        assert_eq!(receiver.emitted.pop(), Some(Vec::new()));
+        assert_eq!(sender.core.peer_stats(receiver_addr), None);
+        assert_eq!(receiver.core.peer_stats(sender_addr), None);
+        let (sender_stats, receiver_stats) = stats.unwrap();
+        (sender_stats, receiver_stats, receiver.emitted)
+    }
+
+    #[test]
+    fn same_seed_same_network() {
+        let deliveries = |seed| {
+            let network = ImpairedNetwork::new(NetworkProfile::STRESS, seed);
+            let from = network.bind_addr();
+            let to = network.bind_addr();
+            let now = Instant::now();
+            for i in 0..100 {
+                network.send(from, to, &[i], now);
+            }
+            let mut received = Vec::new();
+            while let Some((data, _)) = network.recv(to, now + Duration::from_secs(1)) {
+                received.extend(data);
+            }
+            received
+        };
+        let received = deliveries(1);
+        assert_eq!(received, deliveries(1));
+        assert_ne!(received, deliveries(2));
+        // some datagrams were lost, and the rest were reordered.
+        assert!(received.len() < 100);
+        assert!(received.windows(2).any(|pair| pair[0] > pair[1]));
+    }
+
+    #[test]
+    fn duplicates_and_reorders() {
+        let profile = NetworkProfile {
+            reorder: 0.5,
+            duplicate: 0.5,
+            ..NetworkProfile::NORMAL
+        };
+        let network = ImpairedNetwork::new(profile, 7);
+        let from = network.bind_addr();
+        let to = network.bind_addr();
+        let now = Instant::now();
+        for i in 0..20 {
+            network.send(from, to, &[i], now + Duration::from_millis(i as u64));
+        }
+        let mut received = Vec::new();
+        while let Some((data, _)) = network.recv(to, now + Duration::from_secs(1)) {
+            received.extend(data);
+        }
+        assert!(received.len() > 20);
+        assert!(received.windows(2).any(|pair| pair[0] > pair[1]));
+    }
+
+    const PROFILES: [NetworkProfile; 5] = [
+        NetworkProfile::NORMAL,
+        NetworkProfile::ACCEPTABLE,
+        NetworkProfile::DEGRADED,
+        NetworkProfile::HORRIBLE,
+        NetworkProfile::STRESS,
+    ];
+
+    /// Parses a duration like `20ms` from `tc netem`.
+    fn netem_millis(arg: &str) -> Duration {
+        Duration::from_millis(arg.trim_end_matches("ms").parse().unwrap())
+    }
+
+    #[test]
+    fn profiles_match_do_run() {
+        let names = ["normal", "acceptable", "degraded", "horrible", "stress"];
+        let script = include_str!("../../../tools/do-run.sh");
+        let mut case = None;
+        let mut found = Vec::new();
+        for line in script.lines().map(str::trim) {
+            if let Some(name) = line.strip_suffix(')') {
+                case = Some(name);
+            }
+            // every case applies the same rule to the consumer and the producer.
+            let args: Vec<&str> = match line.split_once("netem delay ") {
+                Some((_, args)) if line.contains("consumer") => args.split(' ').collect(),
+                _ => continue,
+            };
+            let profile = NetworkProfile {
+                delay: netem_millis(args[0]),
+                jitter: netem_millis(args[1]),
+                loss: args[4].trim_end_matches('%').parse::<f64>().unwrap() / 100.0,
+                ..NetworkProfile::PERFECT
+            };
+            found.push((case.unwrap(), profile));
+        }
+        let expected: Vec<_> = names
+            .iter()
+            .copied()
+            .zip(PROFILES.iter().copied())
+            .collect();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn bursts_under_every_profile() {
+        for profile in PROFILES.iter() {
+            let burst = Duration::from_millis(0);
+            let (sender, receiver) = simulate(*profile, HeaderFormat::Extended, burst, 1, 200);
+            assert_eq!(sender.payload_bytes_sent, 200);
+            assert_eq!(receiver.payload_bytes_received, 200);
+            assert!(sender.retransmissions > 0);
+        }
+    }
+
+    #[test]
+    fn paced_under_every_profile() {
+        // the compact header only has room for a few sequence numbers, which come round again
+        // while acks and retransmissions from their last lap are still delayed by the network.
+        for profile in PROFILES.iter() {
+            let interval = Duration::from_millis(100);
+            let (sender, receiver) = simulate(*profile, HeaderFormat::Compact, interval, 1, 100);
+            assert_eq!(sender.payload_bytes_sent, 100);
+            assert_eq!(receiver.payload_bytes_received, 100);
+        }
+    }
+
+    #[test]
+    fn expired_messages_under_every_profile() {
+        let options = SendOptions {
+            ttl: Some(Duration::from_millis(200)),
+            ..SendOptions::default()
+        };
+        for profile in PROFILES.iter() {
+            let interval = Duration::from_millis(10);
+            let (_, _, emitted) =
+                simulate_with(*profile, HeaderFormat::Extended, interval, 1, 200, options);
+            // whatever arrives in time is still emitted exactly once and in order, and the
+            // retransmissions within the time to live make up for most of the loss.
+            assert!(emitted.windows(2).all(|pair| pair[0] < pair[1]));
+            let least = (200.0 * (1.0 - 3.0 * profile.loss)) as usize;
+            assert!(
+                (least..=200).contains(&emitted.len()),
+                "{} of 200 messages emitted with {}% loss",
+                emitted.len(),
+                profile.loss * 100.0
+            );
+        }
+    }
+
+    #[test]
+    fn wrong_acks_from_reordering() {
+        // acks which overtake each other acknowledge packets which are no longer in flight.
+        let burst = Duration::from_millis(0);
+        let (sender, _) = simulate(
+            NetworkProfile::STRESS,
+            HeaderFormat::Extended,
+            burst,
+            3,
+            200,
+        );
+        assert!(sender.wrong_acks > 0);
+    }
+
+    #[test]
+    fn exhausted_by_duplicates() {
+        // duplicated data is acknowledged again after everything has already been acknowledged,
+        // which leaves the sender exhausted rather than confused.
+        let profile = NetworkProfile {
+            duplicate: 0.1,
+            ..NetworkProfile::ACCEPTABLE
+        };
+        let interval = Duration::from_millis(100);
+        let (sender, receiver) = simulate(profile, HeaderFormat::Compact, interval, 5, 100);
+        assert!(receiver.duplicate_packets > 0);
+        assert_eq!(sender.wrong_acks, 0);
+    }
+}
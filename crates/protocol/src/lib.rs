@@ -1,4 +1,17 @@
+#[cfg(feature = "tokio")]
+pub mod async_lrdp_socket;
 mod client_state;
-mod lrdp_packet;
 
+pub mod datagram_transport;
+pub mod impaired_network;
+pub mod lrdp_config;
+pub mod lrdp_connection;
+pub mod lrdp_delivery;
+pub mod lrdp_error;
+pub mod lrdp_listener;
+pub mod lrdp_packet;
 pub mod lrdp_socket;
+pub mod lrdp_stats;
+pub mod rtt_estimator;
+mod socket_core;
+mod timer_queue;
@@ -0,0 +1,85 @@
+use crate::lrdp_packet::HeaderFormat;
+use std::time::Duration;
+
+/// Options which control the behaviour of an LRDP socket.
+#[derive(Debug, Clone, Copy)]
+pub struct LrdpConfig {
+    /// The header format used for every packet sent and received by the socket. The peer must be
+    /// configured with the same format. In the compact format the MORE FRAGMENTS flag takes the
+    /// place of the ACK number, so acknowledgements are never piggybacked on a fragment which is
+    /// followed by more, and fragmented traffic always needs standalone ACKs. The compact format
+    /// is also unsafe on networks whose delay jitter exceeds a lap of its sequence space.
+    pub header_format: HeaderFormat,
+    /// How long to wait for a peer to acknowledge a SYN or FIN before giving up.
+    pub handshake_timeout: Duration,
+    /// How long a peer can go without sending anything before it is evicted. If this is `None`,
+    /// peers are never evicted.
+    pub idle_timeout: Option<Duration>,
+    /// How long the socket can go without sending anything to a peer before it sends a keepalive.
+    /// This should be shorter than the peer's idle timeout. If this is `None`, keepalives are
+    /// never sent.
+    pub keepalive_interval: Option<Duration>,
+    /// How many times a packet can be retransmitted before the peer is declared unreachable. If
+    /// this is `None`, there is no limit.
+    pub max_retransmissions: Option<u32>,
+    /// How long a packet can go unacknowledged after it was first sent before the peer is declared
+    /// unreachable. If this is `None`, there is no limit.
+    pub max_delivery_time: Option<Duration>,
+    /// How many reliable packets can be queued for a peer before they have been acknowledged. Once
+    /// this many are queued, `send_to` blocks and `try_send_to` fails until acknowledgements
+    /// arrive. This is capped at the window size of the header format, and if it is `None`, the
+    /// window size is used.
+    pub max_in_flight: Option<u16>,
+    /// The largest datagram which is sent for reliable data, including the LRDP header. Messages
+    /// which do not fit are split into fragments and reassembled by the peer, so this should be
+    /// small enough to avoid IP fragmentation on the path to the peer. With the compact header
+    /// format, fragments other than the last cannot carry a piggybacked ACK, so sending fragmented
+    /// messages means sending extra standalone ACKs for the data received from the peer.
+    pub max_datagram_size: usize,
+    /// How long an acknowledgement can be held back so that it can be piggybacked on data sent to
+    /// the peer. A standalone ACK is sent if no data goes out in that time, or straight away if a
+    /// second packet arrives or something arrives out of order. If this is `None`, every data
+    /// packet is acknowledged straight away.
+    pub ack_delay: Option<Duration>,
+    /// Whether to offer selective acknowledgements to peers when opening a connection. They are
+    /// only used if both sides enable them, and let the sender skip retransmitting packets which
+    /// arrived after a lost one. Otherwise acknowledgements never carry anything but the header.
+    pub selective_ack: bool,
+    /// How long stopping the socket waits for the data which is queued for its peers to be
+    /// acknowledged. Anything which is still unacknowledged after this is given up on.
+    pub stop_timeout: Duration,
+}
+
+impl Default for LrdpConfig {
+    fn default() -> Self {
+        Self {
+            header_format: HeaderFormat::default(),
+            handshake_timeout: Duration::from_secs(10),
+            idle_timeout: None,
+            keepalive_interval: None,
+            max_retransmissions: Some(10),
+            max_delivery_time: None,
+            max_in_flight: None,
+            max_datagram_size: 1200,
+            ack_delay: Some(Duration::from_millis(10)),
+            selective_ack: false,
+            stop_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl LrdpConfig {
+    /// The number of reliable packets which can actually be in flight at once for each peer.
+    pub fn in_flight_limit(&self) -> usize {
+        let window = self.header_format.window_size();
+        self.max_in_flight
+            .map_or(window, |max| max.clamp(1, window)) as usize
+    }
+
+    /// The largest payload which fits in a single reliable datagram.
+    pub fn max_fragment_size(&self) -> usize {
+        self.max_datagram_size
+            .saturating_sub(self.header_format.header_len())
+            .max(1)
+    }
+}
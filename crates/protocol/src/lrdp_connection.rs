@@ -0,0 +1,635 @@
+use crate::client_state::{ClientError, ClientState};
+use crate::lrdp_config::LrdpConfig;
+use crate::lrdp_delivery::{Delivery, MessageId, SendOptions};
+use crate::lrdp_error::{LrdpError, LrdpResult};
+use crate::lrdp_packet::{ControlType, HeaderFormat, LrdpPacket, LrdpPacketRef};
+use crate::lrdp_stats::LrdpStats;
+use crate::rtt_estimator::RttEstimator;
+
+pub use crate::client_state::ConnectionState;
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+/// Something which happened on a connection, which the application finds out about through
+/// `LrdpConnection::poll_event`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LrdpEvent {
+    /// The handshake with the peer has finished, so data can be exchanged.
+    Connected,
+    /// A message was received from the peer, either reliably and in order, or unreliably.
+    Message(Vec<u8>),
+    /// The peer closed the connection.
+    PeerClosed,
+    /// The peer acknowledged that the connection was closed.
+    Closed,
+    /// The peer went longer than the idle timeout without sending anything, so the connection was
+    /// given up on.
+    Idle,
+    /// The peer stopped acknowledging packets, so the connection was abandoned.
+    Unreachable,
+    /// The peer acknowledged all of a message which was sent reliably, or the message was given up
+    /// on.
+    Receipt(MessageId, Delivery),
+}
+
+/// Returns the header format which a SYN `packet` asks for, if it names one.
+pub(crate) fn syn_format(packet: &LrdpPacketRef) -> Option<HeaderFormat> {
+    packet
+        .data()
+        .first()
+        .copied()
+        .and_then(HeaderFormat::from_id)
+}
+
+/// The protocol logic of a single LRDP connection, without any I/O, threads or clocks.
+///
+/// The application owns the socket and the timers. It hands every datagram which arrives from the
+/// peer to `handle_datagram`, sends whatever `poll_transmit` writes to its buffer until it returns
+/// `None`, and calls `handle_timeout` once the time returned by `poll_timeout` has come. Messages and changes
+/// to the connection are picked up with `poll_event`. Every method which needs to know the time is
+/// given it, so the connection can just as well be driven by a game loop or in virtual time.
+pub struct LrdpConnection {
+    /// The address of the peer.
+    peer: SocketAddr,
+    /// The address of the peer as a string, used as the log target.
+    target: String,
+    format: HeaderFormat,
+    max_fragment_size: usize,
+    client: ClientState,
+    /// Unreliable packets and control replies which are waiting to be handed out by
+    /// `poll_transmit`.
+    transmits: VecDeque<LrdpPacket>,
+    /// Events which are waiting to be handed out by `poll_event`.
+    events: VecDeque<LrdpEvent>,
+    /// Whether the handshake has finished since the connection was opened or last lost.
+    connected: bool,
+}
+
+impl LrdpConnection {
+    /// Creates a connection with the peer at `peer`, and starts opening it. The SYN and any data
+    /// which is sent before the peer acknowledges it come out of `poll_transmit`.
+    pub fn connect(peer: SocketAddr, config: LrdpConfig) -> Self {
+        let mut connection = Self::accept(peer, config);
+        connection.client.open();
+        connection
+    }
+
+    /// Creates a connection with the peer at `peer`, which is expected to open it. This is the
+    /// case when a SYN has been received from an unknown address, which should then be handed to
+    /// `handle_datagram`.
+    pub fn accept(peer: SocketAddr, config: LrdpConfig) -> Self {
+        Self {
+            peer,
+            target: peer.to_string(),
+            format: config.header_format,
+            max_fragment_size: config.max_fragment_size(),
+            client: ClientState::new(peer, &config),
+            transmits: VecDeque::new(),
+            events: VecDeque::new(),
+            connected: false,
+        }
+    }
+
+    /// Returns the address of the peer.
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Returns the stage of the connection.
+    pub fn state(&self) -> ConnectionState {
+        self.client.state()
+    }
+
+    /// Returns the round trip time estimate for the peer.
+    pub fn rtt(&self) -> &RttEstimator {
+        self.client.rtt()
+    }
+
+    /// Returns the traffic statistics of the connection.
+    pub fn stats(&self) -> LrdpStats {
+        self.client.stats()
+    }
+
+    /// Whether the send queue is full, so nothing more can be sent until the peer has acknowledged
+    /// some of it.
+    pub fn is_full(&self) -> bool {
+        self.client.is_full()
+    }
+
+    /// Whether any data is queued which the peer has not acknowledged yet.
+    pub fn has_queued_data(&self) -> bool {
+        self.client.has_queued_data()
+    }
+
+    /// The number of packets in the send queue, including those which have not been sent yet.
+    pub(crate) fn queued_len(&self) -> usize {
+        self.client.queued_len()
+    }
+
+    /// Returns the options which have been agreed on with the peer.
+    #[cfg(test)]
+    pub(crate) fn options(&self) -> u8 {
+        self.client.options()
+    }
+
+    /// Discards everything which is queued for the peer, and returns the messages which it has not
+    /// acknowledged.
+    pub fn take_undelivered(&mut self) -> Vec<Vec<u8>> {
+        let messages = self
+            .client
+            .take_undelivered()
+            .into_iter()
+            .map(|message| message.into_vec())
+            .collect();
+        self.collect_deliveries();
+        messages
+    }
+
+    /// Whether the message with the given `id` has been queued but not fully acknowledged yet.
+    pub fn is_pending(&self, id: MessageId) -> bool {
+        self.client.is_pending(id)
+    }
+
+    /// Queues `data` to be sent reliably to the peer as a single message. If the send queue is
+    /// full, `LrdpError::QueueFull` is returned and nothing is queued, and if the peer has been
+    /// declared unreachable, `LrdpError::PeerUnreachable` is returned.
+    ///
+    /// The returned id shows up in an `LrdpEvent::Receipt` once the peer has acknowledged the whole
+    /// message, or once the message has been given up on.
+    pub fn send(&mut self, data: &[u8]) -> LrdpResult<MessageId> {
+        self.enqueue(data, None, None)
+    }
+
+    /// Queues `data` to be sent to the peer like `send`, treating it according to `options`. The
+    /// time to live of the message counts from `now`.
+    pub fn send_with(
+        &mut self,
+        data: &[u8],
+        options: SendOptions,
+        now: Instant,
+    ) -> LrdpResult<MessageId> {
+        let deadline = options.ttl.map(|ttl| now + ttl);
+        self.enqueue(data, deadline, options.key)
+    }
+
+    /// Queues `data` as a message which is given up on once `deadline` passes or a newer message
+    /// with the same `key` is queued.
+    fn enqueue(
+        &mut self,
+        data: &[u8],
+        deadline: Option<Instant>,
+        key: Option<u32>,
+    ) -> LrdpResult<MessageId> {
+        if self.client.state() == ConnectionState::Unreachable {
+            return Err(LrdpError::PeerUnreachable);
+        }
+        Ok(self
+            .client
+            .enqueue_message(data, self.max_fragment_size, deadline, key)?)
+    }
+
+    /// Queues `data` to be sent to the peer without any delivery guarantees. It is never
+    /// retransmitted, and the peer emits it without touching its sequence state.
+    pub fn send_unreliable(&mut self, data: &[u8]) {
+        self.transmits
+            .push_back(LrdpPacket::unreliable(data.into()));
+    }
+
+    /// Starts closing the connection. The FIN is sent once all of the queued data has been
+    /// acknowledged, and `LrdpEvent::Closed` follows once the peer has acknowledged it.
+    pub fn close(&mut self) {
+        self.client.close();
+    }
+
+    /// Writes the next datagram which needs to be sent to the peer at `now` to the start of `buf`,
+    /// and returns its length, or `None` once there is nothing more to send. The same buffer can be
+    /// reused for every datagram.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` is too short for the datagram. A buffer of `u16::MAX` bytes can hold any
+    /// datagram, and reliable datagrams are never larger than the configured maximum datagram size.
+    pub fn poll_transmit(&mut self, now: Instant, buf: &mut [u8]) -> Option<usize> {
+        let len = match self.transmits.pop_front() {
+            Some(packet) => packet.encode_into(self.format, buf),
+            None => match self.client.poll_transmit(now, buf) {
+                Ok(len) => len?,
+                Err(_) => {
+                    self.connected = false;
+                    self.collect_deliveries();
+                    self.events.push_back(LrdpEvent::Unreachable);
+                    return None;
+                }
+            },
+        };
+        self.client.record_sent(len);
+        Some(len)
+    }
+
+    /// Returns the time at which `handle_timeout` needs to be called next, or `None` if there is
+    /// nothing to wait for. This should be called once `poll_transmit` has run out of datagrams,
+    /// since packets which have not been sent yet are not taken into account.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        self.client.poll_timeout()
+    }
+
+    /// Returns the next thing which happened on the connection, or `None` if nothing has.
+    pub fn poll_event(&mut self) -> Option<LrdpEvent> {
+        self.events.pop_front()
+    }
+
+    /// Handles the timeout which was returned by `poll_timeout`, at `now`. If the peer has gone
+    /// quiet for longer than the idle timeout, the connection is given up on, and if it has not
+    /// acknowledged the SYN or FIN within the handshake timeout, it is declared unreachable.
+    /// Anything which has timed out is retransmitted by the next `poll_transmit`.
+    pub fn handle_timeout(&mut self, now: Instant) {
+        if self.client.handle_timeout(now).is_err() {
+            self.connected = false;
+            self.collect_deliveries();
+            self.events.push_back(LrdpEvent::Unreachable);
+            return;
+        }
+        let state = self.client.state();
+        if state != ConnectionState::Closed && self.client.is_idle(now) {
+            log::info!(
+                target: &self.target,
+                "Client is idle, evicting it."
+            );
+            self.client.evict();
+            self.collect_deliveries();
+            self.events.push_back(LrdpEvent::Idle);
+        }
+    }
+
+    /// Handles a datagram `buf` which was received from the peer at `now`. If it is not a valid
+    /// LRDP packet, it is dropped and `LrdpError::MalformedPacket` is returned.
+    pub fn handle_datagram(&mut self, now: Instant, buf: &[u8]) -> LrdpResult<()> {
+        let result = self.process_datagram(now, buf);
+        self.collect_deliveries();
+        result
+    }
+
+    /// Turns the deliveries which the client state has found out about into events.
+    fn collect_deliveries(&mut self) {
+        for (id, delivery) in self.client.take_deliveries() {
+            self.events.push_back(LrdpEvent::Receipt(id, delivery));
+        }
+    }
+
+    fn process_datagram(&mut self, now: Instant, buf: &[u8]) -> LrdpResult<()> {
+        let target = &self.target;
+        let format = self.format;
+
+        let packet = match LrdpPacketRef::parse(buf, format) {
+            Ok(packet) => packet,
+            Err(e) => {
+                log::warn!(target: target, "... Sent a malformed packet, ignoring: {}", e);
+                return Err(LrdpError::MalformedPacket);
+            }
+        };
+        log::debug!(target: target, "... Created packet from header {:08b}", buf[0]);
+        let client = &mut self.client;
+        client.last_recv = Some(now);
+        client.record_received(buf.len());
+
+        // unreliable packets are emitted straight away and are never acknowledged.
+        if packet.is_unreliable() {
+            log::info!(target: target, "... Unreliable packet, emitting data.");
+            self.events
+                .push_back(LrdpEvent::Message(packet.data().to_vec()));
+            return Ok(());
+        }
+
+        if let Some(control) = packet.control_type() {
+            log::info!(target: target, "... {:?} received", control);
+            match control {
+                ControlType::Syn => {
+                    // make sure both sides agree on the header format.
+                    let syn_format = syn_format(&packet);
+                    if syn_format != Some(format) {
+                        log::error!(
+                            target: target,
+                            "... Uses header format {:?}, ignoring SYN.",
+                            syn_format
+                        );
+                        return Ok(());
+                    }
+                    client.negotiate(packet.data().get(1).copied().unwrap_or(0));
+                }
+                ControlType::SynAck => {
+                    client.negotiate(packet.data().first().copied().unwrap_or(0));
+                }
+                // the peer has given up on some data, so stop waiting for it.
+                ControlType::Forward => {
+                    if client.state() == ConnectionState::Unreachable {
+                        log::warn!(target: target, "... Has not opened a connection, ignoring.");
+                        return Ok(());
+                    }
+                    match packet.forward_seq() {
+                        Some(seq_num) => {
+                            for message in client.skip_to(seq_num) {
+                                self.events
+                                    .push_back(LrdpEvent::Message(message.into_vec()));
+                            }
+                        }
+                        None => log::warn!(target: target, "... FORWARD has no sequence number."),
+                    }
+                    return Ok(());
+                }
+                _ => {}
+            }
+
+            let previous = client.state();
+            if let Some(reply) = client.recv_control(control, now) {
+                let data: Box<[u8]> = match (reply, client.options()) {
+                    (ControlType::SynAck, options) if options != 0 => Box::new([options]),
+                    _ => Box::new([]),
+                };
+                self.transmits.push_back(LrdpPacket::control(reply, data));
+            }
+            let state = client.state();
+            let event = match control {
+                ControlType::Syn | ControlType::SynAck
+                    if !self.connected && state == ConnectionState::Open =>
+                {
+                    self.connected = true;
+                    Some(LrdpEvent::Connected)
+                }
+                ControlType::Fin => Some(LrdpEvent::PeerClosed),
+                ControlType::FinAck if previous == ConnectionState::Closing => {
+                    Some(LrdpEvent::Closed)
+                }
+                _ => None,
+            };
+            if state == ConnectionState::Closed {
+                log::info!(target: target, "... Connection closed.");
+            }
+            self.events.extend(event);
+            return Ok(());
+        }
+
+        if client.state() == ConnectionState::Unreachable {
+            log::warn!(target: target, "... Has not opened a connection, ignoring.");
+            return Ok(());
+        }
+
+        // check if this packet is ACKing anything.
+        if packet.has_ack() {
+            log::info!(target: target, "... ACK flag was set: {}", packet.ack_num());
+            // acks which are piggybacked on data are repeated on every data packet, so they are
+            // not a sign that anything was lost.
+            let result = if packet.has_data() {
+                client.ack_piggybacked(packet.ack_num(), now)
+            } else {
+                client.selective_ack(packet.ack_num(), packet.sack_bitmap(), now)
+            };
+            match result {
+                // a duplicate ack means the receiver is missing a packet, which will be
+                // retransmitted by the next `poll_transmit`.
+                Err(ClientError::DuplicateAck(_)) => {
+                    log::warn!(
+                        target: target,
+                        "... DuplicateAck {}. Retransmitting missing packet.",
+                        packet.ack_num()
+                    );
+                }
+                // log if there was a bad value but don't do anything. This can happen in some cases
+                // where two acks for the same packet are sent because of high latency on the
+                // network.
+                Err(ClientError::WrongAck(_)) => {
+                    log::warn!(
+                        target: target,
+                        "... WrongAck {}. Ignoring.",
+                        packet.ack_num()
+                    );
+                }
+                _ => {}
+            }
+        }
+
+        // check for any data.
+        if packet.has_data() {
+            log::info!(target: target, "... DATA flag was set: {}", packet.seq_num());
+            match client.recv(packet.seq_num(), packet.data(), packet.more_fragments()) {
+                Ok(emitted) => {
+                    if emitted.is_empty() {
+                        log::info!(
+                            target: target,
+                            "... Seq number is out of order, buffering data."
+                        );
+                    }
+                    for data in emitted {
+                        log::info!(target: target, "... Emitting data.");
+                        self.events.push_back(LrdpEvent::Message(data.into_vec()));
+                    }
+                }
+                // if the seq number is outside of the window then it is most likely a
+                // retransmission of something which has already been received.
+                Err(ClientError::WrongSeq(_, expected)) => {
+                    log::warn!(
+                        target: target,
+                        "... Expected seq num {} but got {}, sending ack.",
+                        expected,
+                        packet.seq_num()
+                    );
+                }
+                // for any other error just drop the connection.
+                Err(_) => {
+                    log::error!(target: target, "... Other error occurred. Dropping connection.");
+                    client.evict();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Hands every datagram which `from` has to send at `now` over to `to`, and returns how many
+    /// there were.
+    fn deliver(from: &mut LrdpConnection, to: &mut LrdpConnection, now: Instant) -> usize {
+        let mut buf = [0u8; u16::MAX as usize];
+        let mut delivered = 0;
+        while let Some(len) = from.poll_transmit(now, &mut buf) {
+            to.handle_datagram(now, &buf[..len]).unwrap();
+            delivered += 1;
+        }
+        delivered
+    }
+
+    /// Returns the next datagram which `connection` has to send at `now`.
+    fn transmit(connection: &mut LrdpConnection, now: Instant) -> Option<Vec<u8>> {
+        let mut buf = [0u8; u16::MAX as usize];
+        let len = connection.poll_transmit(now, &mut buf)?;
+        Some(buf[..len].to_vec())
+    }
+
+    /// Returns every event which is waiting on `connection`.
+    fn events(connection: &mut LrdpConnection) -> Vec<LrdpEvent> {
+        std::iter::from_fn(|| connection.poll_event()).collect()
+    }
+
+    /// Opens a connection from `a` to `b` at `now`.
+    fn connected_pair(config: LrdpConfig, now: Instant) -> (LrdpConnection, LrdpConnection) {
+        let a_addr = "127.0.0.1:1000".parse().unwrap();
+        let b_addr = "127.0.0.1:2000".parse().unwrap();
+        let mut a = LrdpConnection::connect(b_addr, config);
+        let mut b = LrdpConnection::accept(a_addr, config);
+        deliver(&mut a, &mut b, now);
+        deliver(&mut b, &mut a, now);
+        assert_eq!(events(&mut a), vec![LrdpEvent::Connected]);
+        assert_eq!(events(&mut b), vec![LrdpEvent::Connected]);
+        (a, b)
+    }
+
+    #[test]
+    fn exchange_and_close() {
+        let now = Instant::now();
+        let (mut a, mut b) = connected_pair(LrdpConfig::default(), now);
+        assert_eq!(a.state(), ConnectionState::Open);
+
+        let id = a.send(&[1, 2]).unwrap();
+        a.send_unreliable(&[3]);
+        deliver(&mut a, &mut b, now);
+        assert_eq!(
+            events(&mut b),
+            vec![LrdpEvent::Message(vec![3]), LrdpEvent::Message(vec![1, 2])]
+        );
+
+        // the ack waits for the ack delay, in case there is data to piggyback it on.
+        assert_eq!(transmit(&mut b, now), None);
+        let ack_due = b.poll_timeout().unwrap();
+        b.handle_timeout(ack_due);
+        assert_eq!(deliver(&mut b, &mut a, ack_due), 1);
+        assert!(!a.has_queued_data());
+        assert!(!a.is_pending(id));
+        let latency = Delivery::Acknowledged(ack_due - now);
+        assert_eq!(events(&mut a), vec![LrdpEvent::Receipt(id, latency)]);
+
+        a.close();
+        deliver(&mut a, &mut b, ack_due);
+        assert_eq!(events(&mut b), vec![LrdpEvent::PeerClosed]);
+        deliver(&mut b, &mut a, ack_due);
+        assert_eq!(events(&mut a), vec![LrdpEvent::Closed]);
+        assert_eq!(a.state(), ConnectionState::Closed);
+        assert_eq!(b.state(), ConnectionState::Closed);
+    }
+
+    #[test]
+    fn retransmits_in_virtual_time() {
+        let now = Instant::now();
+        let (mut a, mut b) = connected_pair(LrdpConfig::default(), now);
+
+        // the first transmission is lost, so nothing happens until the retransmission timeout.
+        a.send(&[1]).unwrap();
+        assert!(transmit(&mut a, now).is_some());
+        assert_eq!(transmit(&mut a, now), None);
+        let rto = a.poll_timeout().unwrap();
+        assert_eq!(transmit(&mut a, rto - Duration::from_millis(1)), None);
+
+        a.handle_timeout(rto);
+        assert_eq!(deliver(&mut a, &mut b, rto), 1);
+        assert_eq!(events(&mut b), vec![LrdpEvent::Message(vec![1])]);
+        assert_eq!(a.stats().retransmissions, 1);
+    }
+
+    #[test]
+    fn expired_message_is_skipped() {
+        let now = Instant::now();
+        let (mut a, mut b) = connected_pair(LrdpConfig::default(), now);
+
+        // the message never gets through before its time to live runs out.
+        let ttl = Duration::from_millis(20);
+        let options = SendOptions {
+            ttl: Some(ttl),
+            ..SendOptions::default()
+        };
+        let id = a.send_with(&[1], options, now).unwrap();
+        assert!(transmit(&mut a, now).is_some());
+        assert_eq!(a.poll_timeout(), Some(now + ttl));
+
+        // the peer skips over it and acknowledges that, so later messages still get through.
+        a.handle_timeout(now + ttl);
+        assert_eq!(deliver(&mut a, &mut b, now + ttl), 1);
+        assert_eq!(events(&mut b), Vec::new());
+        assert_eq!(deliver(&mut b, &mut a, now + ttl), 1);
+        assert_eq!(
+            events(&mut a),
+            vec![LrdpEvent::Receipt(id, Delivery::Expired)]
+        );
+        assert!(!a.has_queued_data());
+
+        a.send(&[2]).unwrap();
+        deliver(&mut a, &mut b, now + ttl);
+        assert_eq!(events(&mut b), vec![LrdpEvent::Message(vec![2])]);
+    }
+
+    #[test]
+    fn unreachable_and_idle() {
+        let config = LrdpConfig {
+            max_retransmissions: Some(1),
+            idle_timeout: Some(Duration::from_secs(30)),
+            ..LrdpConfig::default()
+        };
+        let now = Instant::now();
+        let (mut a, mut b) = connected_pair(config, now);
+
+        // nothing ever reaches the peer, so it is given up on.
+        let id = a.send(&[1]).unwrap();
+        let mut now = now;
+        while transmit(&mut a, now).is_some() || a.state() != ConnectionState::Unreachable {
+            now = a.poll_timeout().unwrap();
+            a.handle_timeout(now);
+        }
+        assert_eq!(
+            events(&mut a),
+            vec![
+                LrdpEvent::Receipt(id, Delivery::Lost),
+                LrdpEvent::Unreachable
+            ]
+        );
+        assert!(matches!(a.send(&[2]), Err(LrdpError::PeerUnreachable)));
+
+        // the peer has not heard anything since the handshake.
+        let idle = b.poll_timeout().unwrap();
+        b.handle_timeout(idle);
+        assert_eq!(events(&mut b), vec![LrdpEvent::Idle]);
+        assert_eq!(b.state(), ConnectionState::Closed);
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        let config = LrdpConfig {
+            handshake_timeout: Duration::from_secs(1),
+            ..LrdpConfig::default()
+        };
+        let start = Instant::now();
+        let mut a = LrdpConnection::connect("127.0.0.1:1000".parse().unwrap(), config);
+
+        // the SYN is retransmitted until the handshake timeout runs out.
+        let mut now = start;
+        while transmit(&mut a, now).is_some() || a.state() != ConnectionState::Unreachable {
+            now = a.poll_timeout().unwrap();
+            a.handle_timeout(now);
+        }
+        assert_eq!(now, start + config.handshake_timeout);
+        assert_eq!(events(&mut a), vec![LrdpEvent::Unreachable]);
+    }
+
+    #[test]
+    fn malformed_datagram() {
+        let addr = "127.0.0.1:1000".parse().unwrap();
+        let mut connection = LrdpConnection::accept(addr, LrdpConfig::default());
+        assert!(matches!(
+            connection.handle_datagram(Instant::now(), &[]),
+            Err(LrdpError::MalformedPacket)
+        ));
+        assert_eq!(connection.poll_event(), None);
+    }
+}
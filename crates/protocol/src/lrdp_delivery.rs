@@ -0,0 +1,51 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// The id which was given to the most recent message.
+static LAST_MESSAGE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Identifies a message which was sent reliably, so that its delivery can be followed. Ids are
+/// unique across every connection in the process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MessageId(u64);
+
+impl MessageId {
+    /// Returns an id which has not been given to any other message.
+    pub(crate) fn next() -> Self {
+        Self(LAST_MESSAGE_ID.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+}
+
+/// What became of a message which was sent reliably.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// The peer acknowledged all of the message, this long after it was first transmitted.
+    Acknowledged(Duration),
+    /// The message was given up on before the peer acknowledged all of it, because the connection
+    /// was lost or the socket was stopped.
+    Lost,
+    /// The message's time to live ran out before the peer acknowledged all of it, so the peer was
+    /// told to stop waiting for it.
+    Expired,
+    /// A newer message with the same key was sent before the peer acknowledged all of this one, so
+    /// the peer was told to skip it.
+    Superseded,
+}
+
+/// How a message which is sent reliably is treated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SendOptions {
+    /// How long after it was sent the message is still worth delivering. Once this has passed, the
+    /// message is no longer retransmitted and the peer skips over it, so the message is only
+    /// partially reliable. If this is `None`, the message is retransmitted until it is delivered.
+    pub ttl: Option<Duration>,
+    /// The logical key which the message is an update of, for channels where only the latest
+    /// state matters. Sending a message with a key gives up on the older messages with the same
+    /// key which the peer has not acknowledged yet, and the peer skips over them. Since messages
+    /// are emitted in order, the peer never emits an older message after a newer one.
+    pub key: Option<u32>,
+}
+
+/// The delivery of a message, along with the peer it was sent to.
+pub type DeliveryReport = (MessageId, SocketAddr, Delivery);
@@ -0,0 +1,127 @@
+use crate::client_state::ClientError;
+use std::fmt;
+use std::io;
+
+/// The result of an operation on an LRDP socket.
+pub type LrdpResult<T> = Result<T, LrdpError>;
+
+/// An error which can be returned by an LRDP socket.
+#[derive(Debug)]
+pub enum LrdpError {
+    /// The underlying socket returned an error. Handshakes which are not acknowledged in time are
+    /// reported as an I/O error of the `TimedOut` kind.
+    Io(io::Error),
+    /// The send queue for the peer is full. The data can be sent again once the peer has
+    /// acknowledged some of the queued data. This is only returned by `try_send_to`.
+    QueueFull,
+    /// The peer closed the connection.
+    PeerClosed,
+    /// The peer stopped acknowledging packets and has been given up on. Sending to it again will
+    /// try to open a new connection.
+    PeerUnreachable,
+    /// The peer sent a packet which does not make sense in the current state of the connection.
+    MalformedPacket,
+    /// The socket has been stopped, or one of its background tasks has died.
+    SocketStopped,
+}
+
+impl LrdpError {
+    /// Whether the same operation might succeed if it is tried again later. If this is `false`,
+    /// retrying will not help.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Io(e) => matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+            ),
+            Self::QueueFull => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for LrdpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Self::Io(e) => write!(f, "I/O error: {}", e),
+            Self::QueueFull => write!(f, "Send queue is full"),
+            Self::PeerClosed => write!(f, "Peer closed the connection"),
+            Self::PeerUnreachable => write!(f, "Peer is unreachable"),
+            Self::MalformedPacket => write!(f, "Peer sent a malformed packet"),
+            Self::SocketStopped => write!(f, "Socket has been stopped"),
+        }
+    }
+}
+
+impl std::error::Error for LrdpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LrdpError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<LrdpError> for io::Error {
+    fn from(e: LrdpError) -> Self {
+        let kind = match e {
+            LrdpError::Io(e) => return e,
+            LrdpError::QueueFull => io::ErrorKind::WouldBlock,
+            LrdpError::PeerClosed => io::ErrorKind::ConnectionAborted,
+            LrdpError::PeerUnreachable => io::ErrorKind::TimedOut,
+            LrdpError::MalformedPacket => io::ErrorKind::InvalidData,
+            LrdpError::SocketStopped => io::ErrorKind::NotConnected,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+impl From<ClientError> for LrdpError {
+    fn from(e: ClientError) -> Self {
+        match e {
+            ClientError::Unreachable => Self::PeerUnreachable,
+            ClientError::QueueFull => Self::QueueFull,
+            // everything else means the peer's sequence numbers are out of sync with ours.
+            _ => Self::MalformedPacket,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transient() {
+        assert!(LrdpError::QueueFull.is_transient());
+        assert!(LrdpError::from(io::Error::from(io::ErrorKind::WouldBlock)).is_transient());
+        assert!(!LrdpError::PeerUnreachable.is_transient());
+        assert!(!LrdpError::from(io::Error::from(io::ErrorKind::AddrInUse)).is_transient());
+    }
+
+    #[test]
+    fn into_io_error() {
+        let e = io::Error::from(LrdpError::PeerClosed);
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted);
+        let e = io::Error::from(LrdpError::from(io::Error::from(io::ErrorKind::AddrInUse)));
+        assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+    }
+
+    #[test]
+    fn from_client_error() {
+        assert!(matches!(
+            LrdpError::from(ClientError::Unreachable),
+            LrdpError::PeerUnreachable
+        ));
+        assert!(matches!(
+            LrdpError::from(ClientError::WrongAck(3)),
+            LrdpError::MalformedPacket
+        ));
+    }
+}
@@ -0,0 +1,414 @@
+use crate::lrdp_config::LrdpConfig;
+use crate::lrdp_delivery::SendOptions;
+use crate::lrdp_error::{LrdpError, LrdpResult};
+use crate::lrdp_socket::{DeliveryReceipt, LrdpSocket, SocketHandle};
+use crate::lrdp_stats::LrdpStats;
+use crate::socket_core::AddressedBuffer;
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// The most messages which are kept for peers which have not been accepted yet. Anything more is
+/// dropped until the listener catches up.
+const MAX_PENDING: usize = 1024;
+
+/// Where the data which is received from each peer is sent.
+#[derive(Default)]
+struct Routes {
+    /// The channels of the streams which have been accepted.
+    streams: HashMap<SocketAddr, Sender<Vec<u8>>>,
+    /// Data from peers which have opened a connection but have not been accepted yet.
+    pending: HashMap<SocketAddr, Vec<Vec<u8>>>,
+    /// The number of messages in `pending`.
+    pending_len: usize,
+}
+
+/// Hands the data which the socket emits over to the stream for the peer it came from, until the
+/// socket stops.
+fn dispatch(handle: SocketHandle, data_rx: Receiver<AddressedBuffer>, routes: Arc<Mutex<Routes>>) {
+    let this_addr = handle
+        .local_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    loop {
+        // the handle keeps the channel open, so the listener wakes this up once the socket has
+        // stopped, and the socket has to be checked to find out that there is nothing more to come.
+        let (data, addr) = match data_rx.recv() {
+            Ok((data, _)) if data.is_empty() && handle.is_stopping() => break,
+            Ok(emitted) => emitted,
+            Err(_) => break,
+        };
+        let mut routes = match routes.lock() {
+            Ok(routes) => routes,
+            Err(_) => break,
+        };
+        // a peer which is waiting to be accepted may have reopened its connection, in which case
+        // its data belongs to the stream which has not been accepted yet.
+        let waiting = handle.lock().is_ok_and(|core| core.is_waiting(addr));
+        if waiting {
+            // the empty buffer which tells the stream that the connection has ended is always kept.
+            if routes.pending_len >= MAX_PENDING && !data.is_empty() {
+                log::warn!(
+                    target: &this_addr,
+                    "Dropping data from {}, which has not been accepted yet.",
+                    addr.to_string()
+                );
+                continue;
+            }
+            routes.pending_len += 1;
+            routes.pending.entry(addr).or_default().push(data);
+        } else if let Some(stream_tx) = routes.streams.get(&addr) {
+            // if the stream has been dropped then nobody is interested in the peer any more.
+            if stream_tx.send(data).is_err() {
+                routes.streams.remove(&addr);
+            }
+        } else {
+            log::debug!(
+                target: &this_addr,
+                "Dropping data from {}, which has no stream.",
+                addr.to_string()
+            );
+        }
+    }
+    log::trace!(target: &this_addr, "dispatcher thread at end.");
+}
+
+/// An LRDP socket which waits for peers to open connections with it, and hands out a separate
+/// stream for each of them, like a `TcpListener`.
+pub struct LrdpListener {
+    handle: SocketHandle,
+    routes: Arc<Mutex<Routes>>,
+    /// The dispatcher thread, until the listener is stopped.
+    dispatcher: Option<JoinHandle<()>>,
+}
+
+impl LrdpListener {
+    /// Creates a listener bound to `addrs` which uses the default configuration.
+    pub fn bind<A: ToSocketAddrs>(addrs: A) -> LrdpResult<Self> {
+        Self::bind_with_config(addrs, LrdpConfig::default())
+    }
+
+    /// Creates a listener bound to `addrs` which uses the given `config`.
+    pub fn bind_with_config<A: ToSocketAddrs>(addrs: A, config: LrdpConfig) -> LrdpResult<Self> {
+        let (handle, data_rx) = LrdpSocket::bind_with_config(addrs, config)?.into_parts();
+        handle.lock()?.listen();
+        let routes = Arc::new(Mutex::new(Routes::default()));
+
+        // the dispatcher thread splits the data which is received up between the streams.
+        let dispatcher_handle = handle.clone();
+        let dispatcher_routes = routes.clone();
+        let dispatcher =
+            thread::spawn(move || dispatch(dispatcher_handle, data_rx, dispatcher_routes));
+
+        Ok(Self {
+            handle,
+            routes,
+            dispatcher: Some(dispatcher),
+        })
+    }
+
+    /// Blocks until a peer opens a connection, and returns a stream for talking to the peer along
+    /// with its address. Any data which the peer sent before it was accepted can be received from
+    /// the stream.
+    pub fn accept(&self) -> LrdpResult<(LrdpStream, SocketAddr)> {
+        loop {
+            drop(self.handle.wait_while(|core| !core.has_accepted())?);
+            // the routes are locked before the core, in the same order as the dispatcher thread,
+            // so that no data can slip through between accepting the peer and adding its stream.
+            let mut routes = self.routes.lock().map_err(|_| LrdpError::SocketStopped)?;
+            let addr = match self.handle.lock()?.accept() {
+                Some(addr) => addr,
+                None => continue,
+            };
+            let (stream_tx, data_rx) = mpsc::channel();
+            for data in routes.pending.remove(&addr).into_iter().flatten() {
+                routes.pending_len -= 1;
+                let _ = stream_tx.send(data);
+            }
+            routes.streams.insert(addr, stream_tx);
+            let stream = LrdpStream::new(self.handle.clone(), self.routes.clone(), addr, data_rx);
+            return Ok((stream, addr));
+        }
+    }
+
+    /// Returns the address that this listener is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.handle.local_addr()
+    }
+
+    /// Stops the listener and every stream it has handed out, once all of the data which is queued
+    /// for the peers has been acknowledged or the configured `stop_timeout` has passed. Returns the
+    /// messages which were never acknowledged, along with the peer they were for.
+    pub fn stop(mut self) -> Vec<AddressedBuffer> {
+        self.shutdown()
+    }
+
+    /// Stops the socket and waits for the dispatcher thread to finish. If the listener has already
+    /// stopped there is nothing to do.
+    fn shutdown(&mut self) -> Vec<AddressedBuffer> {
+        let dispatcher = match self.dispatcher.take() {
+            Some(dispatcher) => dispatcher,
+            None => return Vec::new(),
+        };
+        let undelivered = self.handle.stop();
+        self.handle.wake_receiver();
+        let _ = dispatcher.join();
+        undelivered
+    }
+}
+
+impl Drop for LrdpListener {
+    /// Stops the listener like `stop` if that has not been done already.
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// A connection with a single peer which was accepted by an `LrdpListener`.
+///
+/// Data can be sent and received as messages with `send` and `recv`, or through `std::io::Read`
+/// and `std::io::Write`, which always send reliably. When reading, the boundaries between messages
+/// are not kept, and the end of the stream is reached once the peer closes the connection.
+pub struct LrdpStream {
+    handle: SocketHandle,
+    routes: Arc<Mutex<Routes>>,
+    peer: SocketAddr,
+    data_rx: Receiver<Vec<u8>>,
+    /// Whether the connection with the peer has ended.
+    ended: bool,
+    /// The last message which was received, which is read from before receiving another one.
+    unread: Vec<u8>,
+    /// How much of the unread message has been read.
+    read_pos: usize,
+}
+
+impl LrdpStream {
+    fn new(
+        handle: SocketHandle,
+        routes: Arc<Mutex<Routes>>,
+        peer: SocketAddr,
+        data_rx: Receiver<Vec<u8>>,
+    ) -> Self {
+        Self {
+            handle,
+            routes,
+            peer,
+            data_rx,
+            ended: false,
+            unread: Vec::new(),
+            read_pos: 0,
+        }
+    }
+
+    /// Returns the address of the peer.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Returns the traffic statistics of the connection with the peer, or `None` if the connection
+    /// is gone.
+    pub fn stats(&self) -> Option<LrdpStats> {
+        self.handle.lock().ok()?.peer_stats(self.peer)
+    }
+
+    /// Sends `data` reliably to the peer. If too many packets are already waiting to be
+    /// acknowledged by the peer, this blocks until there is space for another.
+    pub fn send(&mut self, data: &[u8]) -> LrdpResult<DeliveryReceipt> {
+        self.send_with(data, SendOptions::default())
+    }
+
+    /// Sends `data` to the peer like `send`, treating it according to `options`.
+    pub fn send_with(&mut self, data: &[u8], options: SendOptions) -> LrdpResult<DeliveryReceipt> {
+        self.handle.send_to(self.peer, data, options)
+    }
+
+    /// Sends `data` reliably to the peer like `send`, but never blocks. If too many packets are
+    /// already waiting to be acknowledged by the peer, `LrdpError::QueueFull` is returned.
+    pub fn try_send(&mut self, data: &[u8]) -> LrdpResult<DeliveryReceipt> {
+        self.handle.try_send_to(self.peer, data)
+    }
+
+    /// Receives the next message from the peer. When the peer closes the connection or is evicted
+    /// for being idle, `LrdpError::PeerClosed` is returned, and when it is declared unreachable,
+    /// `LrdpError::PeerUnreachable` is returned.
+    pub fn recv(&mut self) -> LrdpResult<Vec<u8>> {
+        if self.ended {
+            return Err(self
+                .handle
+                .connection_lost(self.peer)
+                .unwrap_or(LrdpError::PeerClosed));
+        }
+        let data = self.data_rx.recv().map_err(|_| LrdpError::SocketStopped)?;
+        if data.is_empty() {
+            if let Some(e) = self.handle.connection_lost(self.peer) {
+                self.ended = true;
+                return Err(e);
+            }
+        }
+        Ok(data)
+    }
+
+    /// Closes the connection with the peer, and blocks until the peer has acknowledged it. Any
+    /// data which is queued for the peer is delivered before the connection is closed.
+    pub fn close(&mut self) -> LrdpResult<()> {
+        self.ended = true;
+        self.handle.close_to(self.peer)
+    }
+}
+
+impl Drop for LrdpStream {
+    /// Closes the connection with the peer if that has not happened already, without waiting for
+    /// the peer to acknowledge it, and stops taking data from the peer.
+    fn drop(&mut self) {
+        if let Ok(mut routes) = self.routes.lock() {
+            routes.streams.remove(&self.peer);
+        }
+        if !self.ended {
+            let _ = self.handle.start_close(self.peer);
+        }
+    }
+}
+
+impl io::Read for LrdpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.read_pos == self.unread.len() {
+            match self.recv() {
+                Ok(data) => {
+                    self.unread = data;
+                    self.read_pos = 0;
+                }
+                // the end of the connection is the end of the stream.
+                Err(LrdpError::PeerClosed) => return Ok(0),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let unread = &self.unread[self.read_pos..];
+        let len = buf.len().min(unread.len());
+        buf[..len].copy_from_slice(&unread[..len]);
+        self.read_pos += len;
+        Ok(len)
+    }
+}
+
+impl io::Write for LrdpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // an empty message would look like the end of the connection to some receivers.
+        if !buf.is_empty() {
+            self.send(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn accept_streams() {
+        let listener = LrdpListener::bind("127.0.0.1:0").unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+        let mut first = LrdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut second = LrdpSocket::bind("127.0.0.1:0").unwrap();
+
+        // data sent before the stream is accepted is kept for it.
+        first.connect(listener_addr).unwrap();
+        first.send(&[1]).unwrap();
+        second.connect(listener_addr).unwrap();
+        second.send(&[2]).unwrap();
+
+        let (mut first_stream, first_addr) = listener.accept().unwrap();
+        let (mut second_stream, second_addr) = listener.accept().unwrap();
+        assert_eq!(first_addr, first.local_addr().unwrap());
+        assert_eq!(second_addr, second.local_addr().unwrap());
+        assert_eq!(first_stream.recv().unwrap(), vec![1]);
+        assert_eq!(second_stream.recv().unwrap(), vec![2]);
+
+        second_stream.send(&[3]).unwrap();
+        assert_eq!(second.recv().unwrap(), vec![3]);
+        second_stream.close().unwrap();
+        assert!(matches!(second.recv(), Err(LrdpError::PeerClosed)));
+
+        first.stop();
+        second.stop();
+        listener.stop();
+    }
+
+    #[test]
+    fn read_and_write() {
+        let listener = LrdpListener::bind("127.0.0.1:0").unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+        let mut client = LrdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(listener_addr).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+
+        client.send(b"hel").unwrap();
+        client.send(b"lo").unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        stream.write_all(b"world").unwrap();
+        assert_eq!(client.recv().unwrap(), b"world");
+
+        // the stream ends once the client closes the connection.
+        client.close_to(listener_addr).unwrap();
+        let mut rest = Vec::new();
+        assert_eq!(stream.read_to_end(&mut rest).unwrap(), 0);
+
+        client.stop();
+        listener.stop();
+    }
+
+    #[test]
+    fn dropping_stream_closes_connection() {
+        let listener = LrdpListener::bind("127.0.0.1:0").unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+        let mut client = LrdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(listener_addr).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        drop(stream);
+        assert!(matches!(client.recv(), Err(LrdpError::PeerClosed)));
+        client.stop();
+    }
+
+    #[test]
+    fn streams_stop_with_listener() {
+        let listener = LrdpListener::bind("127.0.0.1:0").unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+        let mut client = LrdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(listener_addr).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+
+        listener.stop();
+        assert!(matches!(stream.send(&[1]), Err(LrdpError::SocketStopped)));
+        assert!(matches!(
+            stream.try_send(&[1]),
+            Err(LrdpError::SocketStopped)
+        ));
+        assert!(matches!(stream.close(), Err(LrdpError::SocketStopped)));
+        client.stop();
+    }
+
+    #[test]
+    fn dropping_listener_releases_address() {
+        let listener = LrdpListener::bind("127.0.0.1:0").unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+
+        drop(listener);
+        std::net::UdpSocket::bind(listener_addr).unwrap();
+    }
+}
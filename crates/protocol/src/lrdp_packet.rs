@@ -1,3 +1,6 @@
+use std::convert::TryFrom;
+use std::fmt;
+
 /// The bitmask for the DATA flag in a packet.
 const DATA_FLAG: u8 = 0b10000000;
 /// The bitmask for the ACK flag in a packet.
@@ -6,68 +9,248 @@ const ACK_FLAG: u8  = 0b01000000;
 const SEQ_MASK: u8  = 0b00111000;
 /// The bitmask for the acknowledgement number in a packet.
 const ACK_MASK: u8  = 0b00000111;
-/// The maximum possible sequence number.
-const MAX_SEQ: u8   = 0b00000111;
+/// The bitmask for the control type in a packet which has neither the DATA nor the ACK flag set.
+const CONTROL_MASK: u8 = 0b00111111;
+/// The bitmask for the MORE FRAGMENTS flag in a packet which has the DATA flag set. In the compact
+/// format this shares its bit with the acknowledgement number, so it can only be set when the ACK
+/// flag is not.
+const MORE_FRAGMENTS_FLAG: u8 = 0b00000001;
+/// The flag in the options byte of a SYN or SYN-ACK which offers or accepts selective
+/// acknowledgements.
+pub const SELECTIVE_ACK_OPTION: u8 = 0b00000001;
+
+/// The layout of the header at the start of each LRDP packet. Both ends of a connection must use
+/// the same format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HeaderFormat {
+    /// A single byte containing the flags, a 3-bit sequence number and a 3-bit acknowledgement
+    /// number.
+    ///
+    /// The sequence space is so small that a number comes round again after two windows, so a
+    /// sequence number is only reused once the packet which last had it was acknowledged at least
+    /// a retransmission timeout ago. That leaves time for late acks and retransmissions from the
+    /// previous lap to drain, but it is not a guarantee: once the delay jitter of the network
+    /// exceeds a whole lap, a stale ack can still be taken for a new packet, and this format is
+    /// unsafe. Use [`HeaderFormat::Extended`] on such networks.
+    #[default]
+    Compact,
+    /// A byte containing the flags, followed by a 16-bit sequence number and a 16-bit
+    /// acknowledgement number. Unreliable packets still only use a single byte.
+    Extended,
+}
+
+impl HeaderFormat {
+    /// The number of distinct sequence numbers which this format can represent.
+    pub fn seq_space(self) -> u32 {
+        match self {
+            Self::Compact => 8,
+            Self::Extended => 1 << 16,
+        }
+    }
+
+    /// The maximum number of packets which can be in flight at once. For selective repeat to work,
+    /// this can be at most half of the sequence space.
+    pub fn window_size(self) -> u16 {
+        match self {
+            Self::Compact => 4,
+            Self::Extended => 256,
+        }
+    }
+
+    /// The identifier of this format which is sent in SYN packets.
+    pub fn id(self) -> u8 {
+        match self {
+            Self::Compact => 0,
+            Self::Extended => 1,
+        }
+    }
+
+    /// The format with the given identifier, if there is one.
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Self::Compact),
+            1 => Some(Self::Extended),
+            _ => None,
+        }
+    }
+
+    /// The length in bytes of the header of a reliable packet.
+    pub fn header_len(self) -> usize {
+        match self {
+            Self::Compact => 1,
+            Self::Extended => 5,
+        }
+    }
+}
+
+/// The type of a control packet, which is used to open and close connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlType {
+    /// Asks the peer to open a connection. The payload is the sender's header format identifier,
+    /// optionally followed by a byte of the options which the sender supports.
+    Syn,
+    /// Acknowledges a `Syn`. The payload is an optional byte of the options which both sides
+    /// support.
+    SynAck,
+    /// Asks the peer to close the connection once all data has been acknowledged.
+    Fin,
+    /// Acknowledges a `Fin`.
+    FinAck,
+    /// Keeps the connection alive when there is no data to send. This is never acknowledged.
+    Keepalive,
+    /// Tells the peer to stop waiting for the data before a sequence number, because the sender
+    /// has given up on it. The payload is the 16-bit sequence number. The peer acknowledges it with
+    /// an ordinary ACK of the sequence number before it.
+    Forward,
+}
+
+impl ControlType {
+    /// The value of the control bits in the header for this type.
+    fn code(self) -> u8 {
+        match self {
+            Self::Syn => 1,
+            Self::SynAck => 2,
+            Self::Fin => 3,
+            Self::FinAck => 4,
+            Self::Keepalive => 5,
+            Self::Forward => 6,
+        }
+    }
+
+    /// The control type with the given value of the control bits, if there is one.
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(Self::Syn),
+            2 => Some(Self::SynAck),
+            3 => Some(Self::Fin),
+            4 => Some(Self::FinAck),
+            5 => Some(Self::Keepalive),
+            6 => Some(Self::Forward),
+            _ => None,
+        }
+    }
+}
 
 /// A packet which conforms to the LRDP protocol.
+///
+/// A packet with neither the DATA nor the ACK flag set is either a control packet or an unreliable
+/// packet, depending on the remaining bits of the header. Control packets have a non-zero control
+/// type. Unreliable packets have a header of zero, and their payload is delivered as-is and never
+/// acknowledged or retransmitted. Both types of packet always have a single byte header.
+///
+/// A message which is too large for a single packet is split into fragments with consecutive
+/// sequence numbers. Every fragment except the last has the MORE FRAGMENTS flag set.
+///
+/// If selective acknowledgements were negotiated when the connection was opened, a packet with
+/// only the ACK flag set can carry a bitmap of the packets which have been received after the gap
+/// following the ACK number. Bit `n` of the bitmap, counting from the least significant bit of the
+/// first byte, is set if the packet with sequence number `ack_num + 2 + n` has been received.
 #[derive(Debug)]
 pub struct LrdpPacket {
     has_ack: bool,
     has_data: bool,
-    ack_num: u8,
-    seq_num: u8,
+    more_fragments: bool,
+    ack_num: u16,
+    seq_num: u16,
+    control: Option<ControlType>,
     data: Box<[u8]>,
 }
 
 impl LrdpPacket {
-    /// Create an LRDP packet from a received buffer.
-    pub fn from_buffer(buf: &[u8]) -> Self {
-        let has_ack = ACK_FLAG & buf[0] == ACK_FLAG;
-        let has_data = DATA_FLAG & buf[0] == DATA_FLAG;
-        let ack_num = ACK_MASK & buf[0];
-        let seq_num = (SEQ_MASK & buf[0]) >> 3;
-        Self {
-            has_ack,
-            has_data,
-            ack_num,
-            seq_num,
-            data: buf[1..].into(),
-        }
+    /// Parse an LRDP packet from a buffer which was received with the given header `format`,
+    /// copying its payload. The buffer is rejected if it is too short for its header, or if its
+    /// header has a combination of bits which is never sent.
+    pub fn parse(buf: &[u8], format: HeaderFormat) -> Result<Self, PacketError> {
+        LrdpPacketRef::parse(buf, format).map(|packet| packet.to_packet())
     }
 
     /// Create an LRDP packet from the given data and other details.
     ///
     /// + If `ack_num` is not `None`, this packet's ACK bit will be set.
     /// + If `seq_num` is not `None`, this packet's DATA bit will be set.
-    pub fn create(data: Box<[u8]>, ack_num: Option<u8>, seq_num: Option<u8>) -> Self {
+    pub fn create(data: Box<[u8]>, ack_num: Option<u16>, seq_num: Option<u16>) -> Self {
         Self {
             data,
             has_ack: ack_num.is_some(),
             ack_num: ack_num.unwrap_or(0),
             has_data: seq_num.is_some(),
             seq_num: seq_num.unwrap_or(0),
+            more_fragments: false,
+            control: None,
         }
     }
 
-    /// Turn the packet into a buffer which can be sent over the network.
-    pub fn as_buffer(&self) -> Vec<u8> {
-        let mut buf = vec![0u8];
+    /// Marks this packet as a fragment which is followed by more fragments of the same message.
+    /// This only has an effect on packets which have the DATA bit set. In the compact format the
+    /// flag takes the place of the ACK, so any ACK is left out when the packet is encoded.
+    pub fn with_more_fragments(mut self) -> Self {
+        self.more_fragments = self.has_data;
+        self
+    }
 
-        // set flags.
-        if self.has_ack() {
-            buf[0] |= ACK_FLAG;
-            buf[0] |= ACK_MASK & self.ack_num;
-        }
-        if self.has_data() {
-            buf[0] |= DATA_FLAG;
-            buf[0] |= (self.seq_num << 3) & SEQ_MASK;
-            // extend with data if needed.
-            buf.extend_from_slice(&self.data)
-        }
+    /// Sets or clears the ACK carried by this packet, so that an acknowledgement can be
+    /// piggybacked on a data packet which is already queued.
+    pub fn set_ack_num(&mut self, ack_num: Option<u16>) {
+        self.has_ack = ack_num.is_some();
+        self.ack_num = ack_num.unwrap_or(0);
+    }
+
+    /// Create a standalone acknowledgement of `ack_num` which carries the given selective
+    /// acknowledgement `bitmap`.
+    pub fn selective_ack(ack_num: u16, bitmap: Box<[u8]>) -> Self {
+        Self::create(bitmap, Some(ack_num), None)
+    }
+
+    /// Create a control packet of the given type with the given data.
+    pub fn control(control: ControlType, data: Box<[u8]>) -> Self {
+        let mut packet = Self::unreliable(data);
+        packet.control = Some(control);
+        packet
+    }
 
+    /// Create a FORWARD control packet which tells the peer to skip ahead to `seq_num`.
+    pub fn forward(seq_num: u16) -> Self {
+        Self::control(ControlType::Forward, Box::new(seq_num.to_be_bytes()))
+    }
+
+    /// Create an unreliable LRDP packet from the given data. The packet's header will have neither
+    /// the ACK nor the DATA bit set.
+    pub fn unreliable(data: Box<[u8]>) -> Self {
+        Self::create(data, None, None)
+    }
+
+    /// Turn the packet into a buffer with the given header `format` which can be sent over the
+    /// network.
+    pub fn as_buffer(&self, format: HeaderFormat) -> Vec<u8> {
+        let packet = self.as_packet_ref();
+        let mut buf = vec![0u8; packet.encoded_len(format)];
+        packet.encode_into(format, &mut buf);
         buf
     }
 
+    /// Writes the packet with the given header `format` to the start of `buf`, and returns the
+    /// number of bytes which were written.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` is shorter than the encoded packet.
+    pub fn encode_into(&self, format: HeaderFormat, buf: &mut [u8]) -> usize {
+        self.as_packet_ref().encode_into(format, buf)
+    }
+
+    /// Returns a view of this packet which borrows its payload.
+    pub fn as_packet_ref(&self) -> LrdpPacketRef<'_> {
+        LrdpPacketRef {
+            has_ack: self.has_ack,
+            has_data: self.has_data,
+            more_fragments: self.more_fragments,
+            ack_num: self.ack_num,
+            seq_num: self.seq_num,
+            control: self.control,
+            data: &self.data,
+        }
+    }
+
     /// Whether or not this packet's ACK bit is set.
     pub fn has_ack(&self) -> bool {
         self.has_ack
@@ -78,36 +261,346 @@ impl LrdpPacket {
         self.has_data
     }
 
+    /// Whether or not this packet is followed by more fragments of the same message.
+    pub fn more_fragments(&self) -> bool {
+        self.more_fragments
+    }
+
+    /// Whether or not this packet is unreliable. This is the case when neither the ACK nor the DATA
+    /// bit is set and the packet is not a control packet.
+    pub fn is_unreliable(&self) -> bool {
+        !self.has_ack && !self.has_data && self.control.is_none()
+    }
+
+    /// The type of this packet if it is a control packet.
+    pub fn control_type(&self) -> Option<ControlType> {
+        self.control
+    }
+
     /// The ACK number of this packet.
-    pub fn ack_num(&self) -> u8 {
+    pub fn ack_num(&self) -> u16 {
         self.ack_num
     }
 
     /// The SEQ number of this packet.
-    pub fn seq_num(&self) -> u8 {
+    pub fn seq_num(&self) -> u16 {
         self.seq_num
     }
 
     /// The data in this packet.
-    pub fn data(&self) -> &Box<[u8]> {
+    pub fn data(&self) -> &[u8] {
         &self.data
     }
+
+    /// The selective acknowledgement bitmap of this packet, which is empty unless this is a
+    /// standalone ACK.
+    pub fn sack_bitmap(&self) -> &[u8] {
+        self.as_packet_ref().sack_bitmap()
+    }
+
+    /// The sequence number which the peer should skip ahead to, if this is a valid FORWARD packet.
+    pub fn forward_seq(&self) -> Option<u16> {
+        self.as_packet_ref().forward_seq()
+    }
+}
+
+/// A view of an LRDP packet which borrows its payload from the buffer it was parsed from, so that
+/// received datagrams can be handled without copying them.
+#[derive(Debug, Clone, Copy)]
+pub struct LrdpPacketRef<'a> {
+    has_ack: bool,
+    has_data: bool,
+    more_fragments: bool,
+    ack_num: u16,
+    seq_num: u16,
+    control: Option<ControlType>,
+    data: &'a [u8],
 }
 
+impl<'a> LrdpPacketRef<'a> {
+    /// Parse an LRDP packet in place from a buffer which was received with the given header
+    /// `format`. The buffer is rejected if it is too short for its header, or if its header has a
+    /// combination of bits which is never sent.
+    pub fn parse(buf: &'a [u8], format: HeaderFormat) -> Result<Self, PacketError> {
+        let header = *buf.first().ok_or(PacketError::Empty)?;
+        let has_ack = ACK_FLAG & header == ACK_FLAG;
+        let has_data = DATA_FLAG & header == DATA_FLAG;
+        // control and unreliable packets only ever have a single byte header.
+        if !has_ack && !has_data {
+            let code = CONTROL_MASK & header;
+            let control = match code {
+                0 => None,
+                _ => Some(ControlType::from_code(code).ok_or(PacketError::UnknownControl(code))?),
+            };
+            return Ok(Self {
+                has_ack,
+                has_data,
+                more_fragments: false,
+                ack_num: 0,
+                seq_num: 0,
+                control,
+                data: &buf[1..],
+            });
+        }
+        if buf.len() < format.header_len() {
+            return Err(PacketError::Truncated(buf.len()));
+        }
+        let (seq_num, ack_num) = match format {
+            HeaderFormat::Compact => (
+                ((SEQ_MASK & header) >> 3) as u16,
+                (ACK_MASK & header) as u16,
+            ),
+            HeaderFormat::Extended => (
+                u16::from_be_bytes([buf[1], buf[2]]),
+                u16::from_be_bytes([buf[3], buf[4]]),
+            ),
+        };
+        let more_fragments = match format {
+            HeaderFormat::Compact => has_data && !has_ack && MORE_FRAGMENTS_FLAG & header != 0,
+            HeaderFormat::Extended => MORE_FRAGMENTS_FLAG & header != 0,
+        };
+        // a standalone ack never has a sequence number, and in the extended format the only flag
+        // besides DATA and ACK is MORE FRAGMENTS, which needs DATA.
+        let invalid = match format {
+            HeaderFormat::Compact => !has_data && seq_num != 0,
+            HeaderFormat::Extended => {
+                header & CONTROL_MASK & !MORE_FRAGMENTS_FLAG != 0
+                    || (!has_data && (seq_num != 0 || more_fragments))
+            }
+        };
+        if invalid {
+            return Err(PacketError::InvalidHeader(header));
+        }
+        Ok(Self {
+            has_ack,
+            has_data,
+            more_fragments,
+            ack_num,
+            seq_num,
+            control: None,
+            data: &buf[format.header_len()..],
+        })
+    }
+
+    /// Create a standalone acknowledgement of `ack_num` which carries the given selective
+    /// acknowledgement `bitmap`, without copying it.
+    pub fn selective_ack(ack_num: u16, bitmap: &'a [u8]) -> Self {
+        Self {
+            has_ack: true,
+            has_data: false,
+            more_fragments: false,
+            ack_num,
+            seq_num: 0,
+            control: None,
+            data: bitmap,
+        }
+    }
+
+    /// Create a control packet of the given type with the given data, without copying it.
+    pub fn control(control: ControlType, data: &'a [u8]) -> Self {
+        Self {
+            control: Some(control),
+            ..Self::unreliable(data)
+        }
+    }
+
+    /// Create an unreliable LRDP packet from the given data, without copying it.
+    pub fn unreliable(data: &'a [u8]) -> Self {
+        Self {
+            has_ack: false,
+            has_data: false,
+            more_fragments: false,
+            ack_num: 0,
+            seq_num: 0,
+            control: None,
+            data,
+        }
+    }
+
+    /// Copies the payload of this packet into an owned packet.
+    pub fn to_packet(&self) -> LrdpPacket {
+        LrdpPacket {
+            has_ack: self.has_ack,
+            has_data: self.has_data,
+            more_fragments: self.more_fragments,
+            ack_num: self.ack_num,
+            seq_num: self.seq_num,
+            control: self.control,
+            data: self.data.into(),
+        }
+    }
+
+    /// The number of bytes which the packet takes up with the given header `format`.
+    pub fn encoded_len(&self, format: HeaderFormat) -> usize {
+        let header_len = if self.has_ack || self.has_data {
+            format.header_len()
+        } else {
+            1
+        };
+        header_len + self.data.len()
+    }
+
+    /// Writes the packet with the given header `format` to the start of `buf`, and returns the
+    /// number of bytes which were written. In the compact format, a packet which is followed by
+    /// more fragments is written without its ACK, since the flag takes the place of the ACK
+    /// number.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` is shorter than the encoded packet.
+    pub fn encode_into(&self, format: HeaderFormat, buf: &mut [u8]) -> usize {
+        let len = self.encoded_len(format);
+        let mut header = 0u8;
+        let has_ack = self.has_ack && !(self.more_fragments && format == HeaderFormat::Compact);
+
+        // set flags.
+        if has_ack {
+            header |= ACK_FLAG;
+        }
+        if self.has_data {
+            header |= DATA_FLAG;
+        }
+        if let Some(control) = self.control {
+            header |= CONTROL_MASK & control.code();
+        }
+        if self.more_fragments {
+            header |= MORE_FRAGMENTS_FLAG;
+        }
+        // set the sequence and acknowledgement numbers.
+        let mut header_len = 1;
+        if has_ack || self.has_data {
+            match format {
+                HeaderFormat::Compact => {
+                    if has_ack {
+                        header |= ACK_MASK & self.ack_num as u8;
+                    }
+                    header |= ((self.seq_num as u8) << 3) & SEQ_MASK;
+                }
+                HeaderFormat::Extended => {
+                    buf[1..3].copy_from_slice(&self.seq_num.to_be_bytes());
+                    buf[3..5].copy_from_slice(&self.ack_num.to_be_bytes());
+                    header_len = 5;
+                }
+            }
+        }
+        buf[0] = header;
+        // add the data, which is the selective acknowledgement bitmap for a standalone ACK.
+        buf[header_len..len].copy_from_slice(self.data);
+
+        len
+    }
+
+    /// Whether or not this packet's ACK bit is set.
+    pub fn has_ack(&self) -> bool {
+        self.has_ack
+    }
+
+    /// Whether or not this packet's DATA bit is set.
+    pub fn has_data(&self) -> bool {
+        self.has_data
+    }
+
+    /// Whether or not this packet is followed by more fragments of the same message.
+    pub fn more_fragments(&self) -> bool {
+        self.more_fragments
+    }
+
+    /// Whether or not this packet is unreliable. This is the case when neither the ACK nor the DATA
+    /// bit is set and the packet is not a control packet.
+    pub fn is_unreliable(&self) -> bool {
+        !self.has_ack && !self.has_data && self.control.is_none()
+    }
+
+    /// The type of this packet if it is a control packet.
+    pub fn control_type(&self) -> Option<ControlType> {
+        self.control
+    }
+
+    /// The ACK number of this packet.
+    pub fn ack_num(&self) -> u16 {
+        self.ack_num
+    }
+
+    /// The SEQ number of this packet.
+    pub fn seq_num(&self) -> u16 {
+        self.seq_num
+    }
+
+    /// The data in this packet, borrowed from the buffer it was parsed from.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// The selective acknowledgement bitmap of this packet, which is empty unless this is a
+    /// standalone ACK.
+    pub fn sack_bitmap(&self) -> &'a [u8] {
+        if self.has_ack && !self.has_data {
+            self.data
+        } else {
+            &[]
+        }
+    }
+
+    /// The sequence number which the peer should skip ahead to, if this is a valid FORWARD packet.
+    pub fn forward_seq(&self) -> Option<u16> {
+        match (self.control, self.data) {
+            (Some(ControlType::Forward), [high, low]) => Some(u16::from_be_bytes([*high, *low])),
+            _ => None,
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for LrdpPacket {
+    type Error = PacketError;
+
+    /// Parse a packet with the default compact header format.
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        Self::parse(buf, HeaderFormat::Compact)
+    }
+}
+
+/// The reason why a datagram could not be parsed as an LRDP packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketError {
+    /// The datagram was empty.
+    Empty,
+    /// The datagram, which had the given length, was too short for its header.
+    Truncated(usize),
+    /// The header had neither the DATA nor the ACK flag set, and an unknown control type.
+    UnknownControl(u8),
+    /// The header had a combination of bits which is never sent.
+    InvalidHeader(u8),
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Self::Empty => write!(f, "Packet is empty."),
+            Self::Truncated(len) => {
+                write!(f, "Packet of {} bytes is too short for its header.", len)
+            }
+            Self::UnknownControl(code) => write!(f, "Unknown control type {}.", code),
+            Self::InvalidHeader(header) => write!(f, "Invalid header {:08b}.", header),
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
-    fn test_from_buffer_ack() {
-        let packet = LrdpPacket::from_buffer(&[0b01000010]);
+    fn test_parse_ack() {
+        let packet = LrdpPacket::parse(&[0b01000010], HeaderFormat::Compact).unwrap();
         assert!(packet.has_ack());
         assert_eq!(packet.ack_num(), 2);
     }
 
     #[test]
-    fn test_from_buffer_data() {
-        let packet = LrdpPacket::from_buffer(&[0b10110000, 1, 2, 3]);
+    fn test_parse_data() {
+        let packet = LrdpPacket::parse(&[0b10110000, 1, 2, 3], HeaderFormat::Compact).unwrap();
         assert!(packet.has_data());
         assert_eq!(packet.seq_num(), 6);
         assert_eq!(packet.data.as_ref(), &[1, 2, 3]);
@@ -126,7 +619,186 @@ mod tests {
     #[test]
     fn test_as_buffer() {
         let packet = LrdpPacket::create(Box::new([4, 5, 6]), None, Some(1));
-        let buf = packet.as_buffer();
+        let buf = packet.as_buffer(HeaderFormat::Compact);
         assert_eq!(buf.as_slice(), &[0b10001000, 4, 5, 6]);
     }
+
+    #[test]
+    fn test_unreliable() {
+        let packet = LrdpPacket::unreliable(Box::new([7, 8]));
+        assert!(packet.is_unreliable());
+        assert_eq!(
+            packet.as_buffer(HeaderFormat::Compact).as_slice(),
+            &[0b00000000, 7, 8]
+        );
+
+        let packet = LrdpPacket::parse(&[0b00000000, 7, 8], HeaderFormat::Compact).unwrap();
+        assert!(packet.is_unreliable());
+        assert_eq!(packet.data(), &[7, 8]);
+    }
+
+    #[test]
+    fn test_extended() {
+        let packet = LrdpPacket::create(Box::new([4, 5, 6]), Some(0x0102), Some(0x0304));
+        let buf = packet.as_buffer(HeaderFormat::Extended);
+        assert_eq!(buf.as_slice(), &[0b11000000, 3, 4, 1, 2, 4, 5, 6]);
+
+        let packet = LrdpPacket::parse(&buf, HeaderFormat::Extended).unwrap();
+        assert!(packet.has_data());
+        assert!(packet.has_ack());
+        assert_eq!(packet.seq_num(), 0x0304);
+        assert_eq!(packet.ack_num(), 0x0102);
+        assert_eq!(packet.data(), &[4, 5, 6]);
+    }
+
+    #[test]
+    fn test_more_fragments() {
+        let packet = LrdpPacket::create(Box::new([1]), None, Some(2)).with_more_fragments();
+        let buf = packet.as_buffer(HeaderFormat::Compact);
+        assert_eq!(buf, vec![0b10010001, 1]);
+        assert!(LrdpPacket::parse(&buf, HeaderFormat::Compact)
+            .unwrap()
+            .more_fragments());
+
+        // in the compact format the bit is part of the ack number when the ACK flag is set.
+        let packet = LrdpPacket::parse(&[0b11010001], HeaderFormat::Compact).unwrap();
+        assert!(!packet.more_fragments());
+        assert_eq!(packet.ack_num(), 1);
+
+        // the extended format has room for both.
+        let packet = LrdpPacket::create(Box::new([1]), Some(3), Some(2)).with_more_fragments();
+        let buf = packet.as_buffer(HeaderFormat::Extended);
+        assert_eq!(buf, vec![0b11000001, 0, 2, 0, 3, 1]);
+        let packet = LrdpPacket::parse(&buf, HeaderFormat::Extended).unwrap();
+        assert!(packet.more_fragments());
+        assert_eq!(packet.ack_num(), 3);
+
+        // the compact format has no room for the ack, so it is left out.
+        let packet = LrdpPacket::create(Box::new([1]), Some(3), Some(2)).with_more_fragments();
+        let buf = packet.as_buffer(HeaderFormat::Compact);
+        assert_eq!(buf, vec![0b10010001, 1]);
+        let packet = LrdpPacket::parse(&buf, HeaderFormat::Compact).unwrap();
+        assert!(!packet.has_ack());
+        assert!(packet.more_fragments());
+    }
+
+    #[test]
+    fn test_selective_ack() {
+        let packet = LrdpPacket::selective_ack(2, Box::new([0b00000101]));
+        let buf = packet.as_buffer(HeaderFormat::Compact);
+        assert_eq!(buf, vec![0b01000010, 0b00000101]);
+        let packet = LrdpPacket::parse(&buf, HeaderFormat::Compact).unwrap();
+        assert_eq!(packet.ack_num(), 2);
+        assert_eq!(packet.sack_bitmap(), &[0b00000101]);
+
+        // data packets never carry a bitmap.
+        let packet = LrdpPacket::parse(&[0b11000010, 5], HeaderFormat::Compact).unwrap();
+        assert!(packet.sack_bitmap().is_empty());
+    }
+
+    #[test]
+    fn test_encode_into() {
+        let packet = LrdpPacket::create(Box::new([4, 5, 6]), Some(0x0102), Some(0x0304));
+        let mut buf = [0u8; 16];
+        let len = packet.encode_into(HeaderFormat::Extended, &mut buf);
+        assert_eq!(&buf[..len], &[0b11000000, 3, 4, 1, 2, 4, 5, 6]);
+
+        let len = LrdpPacket::control(ControlType::FinAck, Box::new([]))
+            .encode_into(HeaderFormat::Extended, &mut buf);
+        assert_eq!(&buf[..len], &[0b00000100]);
+
+        // packets can be encoded straight from borrowed data.
+        let len = LrdpPacketRef::selective_ack(2, &[0b00000101])
+            .encode_into(HeaderFormat::Compact, &mut buf);
+        assert_eq!(&buf[..len], &[0b01000010, 0b00000101]);
+        let len = LrdpPacketRef::unreliable(&[7, 8]).encode_into(HeaderFormat::Extended, &mut buf);
+        assert_eq!(&buf[..len], &[0b00000000, 7, 8]);
+    }
+
+    #[test]
+    fn test_packet_ref() {
+        let buf = [0b10010001, 1, 2];
+        let packet = LrdpPacketRef::parse(&buf, HeaderFormat::Compact).unwrap();
+        assert_eq!(packet.seq_num(), 2);
+        assert!(packet.more_fragments());
+        // the payload is borrowed from the buffer rather than copied.
+        assert_eq!(packet.data().as_ptr(), buf[1..].as_ptr());
+
+        let mut encoded = [0u8; 3];
+        assert_eq!(packet.encode_into(HeaderFormat::Compact, &mut encoded), 3);
+        assert_eq!(encoded, buf);
+        assert_eq!(packet.to_packet().as_buffer(HeaderFormat::Compact), buf);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert_eq!(
+            LrdpPacket::parse(&[], HeaderFormat::Compact).unwrap_err(),
+            PacketError::Empty
+        );
+        assert_eq!(
+            LrdpPacket::parse(&[0b10000000, 0, 1], HeaderFormat::Extended).unwrap_err(),
+            PacketError::Truncated(3)
+        );
+        assert_eq!(
+            LrdpPacket::try_from(&[0b00111111][..]).unwrap_err(),
+            PacketError::UnknownControl(63)
+        );
+        // a standalone ack cannot have a sequence number.
+        assert_eq!(
+            LrdpPacket::try_from(&[0b01001000][..]).unwrap_err(),
+            PacketError::InvalidHeader(0b01001000)
+        );
+        assert_eq!(
+            LrdpPacket::parse(&[0b01000001, 0, 0, 0, 1], HeaderFormat::Extended).unwrap_err(),
+            PacketError::InvalidHeader(0b01000001)
+        );
+        assert_eq!(
+            LrdpPacket::parse(&[0b10000100, 0, 0, 0, 0], HeaderFormat::Extended).unwrap_err(),
+            PacketError::InvalidHeader(0b10000100)
+        );
+
+        // control and unreliable packets always have a single byte header.
+        let packet = LrdpPacket::parse(&[0b00000001], HeaderFormat::Extended).unwrap();
+        assert_eq!(packet.control_type(), Some(ControlType::Syn));
+    }
+
+    #[test]
+    fn test_extended_unreliable() {
+        let packet = LrdpPacket::unreliable(Box::new([7, 8]));
+        assert_eq!(
+            packet.as_buffer(HeaderFormat::Extended).as_slice(),
+            &[0, 7, 8]
+        );
+    }
+
+    #[test]
+    fn test_control() {
+        let packet = LrdpPacket::control(ControlType::Syn, Box::new([1]));
+        assert!(!packet.is_unreliable());
+        for format in &[HeaderFormat::Compact, HeaderFormat::Extended] {
+            let buf = packet.as_buffer(*format);
+            assert_eq!(buf.as_slice(), &[0b00000001, 1]);
+
+            let packet = LrdpPacket::parse(&buf, *format).unwrap();
+            assert_eq!(packet.control_type(), Some(ControlType::Syn));
+            assert_eq!(packet.data(), &[1]);
+        }
+        let packet = LrdpPacket::parse(&[0b00000100], HeaderFormat::Compact).unwrap();
+        assert_eq!(packet.control_type(), Some(ControlType::FinAck));
+    }
+
+    #[test]
+    fn test_forward() {
+        let buf = LrdpPacket::forward(300).as_buffer(HeaderFormat::Extended);
+        assert_eq!(buf.as_slice(), &[0b00000110, 1, 44]);
+        let packet = LrdpPacketRef::parse(&buf, HeaderFormat::Extended).unwrap();
+        assert_eq!(packet.control_type(), Some(ControlType::Forward));
+        assert_eq!(packet.forward_seq(), Some(300));
+
+        // the sequence number has to be there in full.
+        let packet = LrdpPacket::parse(&[0b00000110, 1], HeaderFormat::Compact).unwrap();
+        assert_eq!(packet.forward_seq(), None);
+        assert_eq!(LrdpPacket::forward(3).forward_seq(), Some(3));
+    }
 }
@@ -1,70 +1,578 @@
-use crate::client_state::ClientError;
-use crate::client_state::ClientState;
-use crate::lrdp_packet::LrdpPacket;
+use crate::client_state::ConnectionState;
+use crate::datagram_transport::{self, DatagramTransport};
+use crate::lrdp_config::LrdpConfig;
+use crate::lrdp_delivery::{Delivery, DeliveryReport, MessageId, SendOptions};
+use crate::lrdp_error::{LrdpError, LrdpResult};
+use crate::lrdp_stats::LrdpStats;
+use crate::rtt_estimator::RttEstimator;
+use crate::socket_core::{AddressedBuffer, Output, SocketCore};
+use crate::timer_queue::TimerQueue;
 
-use log;
 use std::collections::HashMap;
+use std::io;
 use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
-use std::sync::mpsc::{Receiver, RecvError, RecvTimeoutError, Sender};
-use std::sync::{mpsc, Arc, Mutex};
-use std::thread;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, SyncSender};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
 
-/// The minimum number of milliseconds which can elapse before a client will retransmit an
-/// unacknowledged packet.
-const RESEND_DELAY: u128 = 300;
-
 /// The result which can be returned by a thread that the LRDP socket runs.
 type ThreadResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
 
-/// A buffer of data which has an address associated with it.
-type AddressedBuffer = (Vec<u8>, SocketAddr);
+/// How many buffers the UDP reader thread can fill with datagrams before the reader thread has
+/// handled them. Once every buffer is in use, the UDP reader thread waits for one to be handed back.
+const READ_BUFFERS: usize = 8;
 
 /// A `T` which has been wrapped in an `Arc` and a `Mutex` so that it may be shared across threads.
 type Shared<T> = Arc<Mutex<T>>;
 
+/// A datagram which was received from the address, held in a buffer along with its length. The
+/// buffer is handed back to the UDP reader thread once the datagram has been handled, so that it
+/// can be reused instead of allocating a new one for every datagram.
+type Datagram = (Vec<u8>, usize, SocketAddr);
+
+/// A message for the timer thread.
+enum TimerMessage {
+    /// The client at the address needs attention at the given time.
+    Schedule(SocketAddr, Instant),
+    /// The socket is stopping.
+    Stop,
+}
+
 /// Turns the `item` into a `Shared` version of itself.
 fn shared<T>(item: T) -> Shared<T> {
     Arc::new(Mutex::new(item))
 }
 
-pub struct LrdpSocket {
-    sender_tx: Sender<()>,
-    reader_tx: Sender<Option<AddressedBuffer>>,
-    udp_socket: UdpSocket,
-    clients: Shared<HashMap<SocketAddr, ClientState>>,
+/// Sends the datagrams of the locked `core` over `socket`, and acts on the rest of its output by
+/// emitting data on `data_tx`, reporting deliveries on `receipt_txs`, notifying `state_changed` and
+/// scheduling timers on `timer_tx`. Everything but the datagrams which could not be sent is acted
+/// on even if sending fails.
+fn flush<T: DatagramTransport>(
+    socket: &T,
+    core: &mut SocketCore,
+    mut out: Output,
+    data_tx: &Sender<AddressedBuffer>,
+    receipt_txs: &Mutex<Vec<Sender<DeliveryReport>>>,
+    state_changed: &Condvar,
+    timer_tx: &Sender<TimerMessage>,
+) -> io::Result<()> {
+    let sent = core.transmit(Instant::now(), &mut out, |buf, addr| {
+        socket.send_to(buf, addr)
+    });
+    for emitted in out.emitted {
+        // emit data. Don't really care about the result.
+        let _ = data_tx.send(emitted);
+    }
+    let receipts = out.receipts;
+    if !receipts.is_empty() {
+        if let Ok(mut receipt_txs) = receipt_txs.lock() {
+            // subscribers which have gone away are forgotten about.
+            receipt_txs.retain(|receipt_tx| {
+                receipts
+                    .iter()
+                    .all(|receipt| receipt_tx.send(*receipt).is_ok())
+            });
+        }
+    }
+    for (addr, deadline) in out.timers {
+        let _ = timer_tx.send(TimerMessage::Schedule(addr, deadline));
+    }
+    if out.state_changed {
+        state_changed.notify_all();
+    }
+    sent
+}
+
+/// Resolves `addr` to the first socket address it refers to.
+fn resolve<A: ToSocketAddrs>(addr: A) -> LrdpResult<SocketAddr> {
+    addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No address to send to").into())
+}
+
+/// The parts of an LRDP socket which are needed to talk to its peers. This is shared by the socket
+/// and the streams which are accepted by a listener.
+pub(crate) struct SocketHandle<T = UdpSocket> {
+    timer_tx: Sender<TimerMessage>,
+    reader_tx: SyncSender<Option<Datagram>>,
+    transport: Arc<T>,
+    core: Shared<SocketCore>,
+    /// Notified whenever the connection state of a client changes, or space is freed in its send
+    /// queue.
+    state_changed: Arc<Condvar>,
+    data_tx: Sender<AddressedBuffer>,
+    /// Subscribers which are told about every message which is acknowledged or given up on.
+    receipt_txs: Shared<Vec<Sender<DeliveryReport>>>,
+    config: LrdpConfig,
+    /// The threads which run the socket, until they are joined when it stops.
+    threads: Shared<Vec<JoinHandle<ThreadResult>>>,
+    /// Set once the socket is stopping, so that threads which cannot be sent a message know to
+    /// finish.
+    stopping: Arc<AtomicBool>,
+}
+
+// the transport is shared rather than cloned, so it does not need to be `Clone` itself.
+impl<T> Clone for SocketHandle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            timer_tx: self.timer_tx.clone(),
+            reader_tx: self.reader_tx.clone(),
+            transport: self.transport.clone(),
+            core: self.core.clone(),
+            state_changed: self.state_changed.clone(),
+            data_tx: self.data_tx.clone(),
+            receipt_txs: self.receipt_txs.clone(),
+            config: self.config,
+            threads: self.threads.clone(),
+            stopping: self.stopping.clone(),
+        }
+    }
+}
+
+impl<T: DatagramTransport> SocketHandle<T> {
+    /// Locks the core. This only fails if one of the socket's threads has died while holding it.
+    pub(crate) fn lock(&self) -> LrdpResult<MutexGuard<'_, SocketCore>> {
+        self.core.lock().map_err(|_| LrdpError::SocketStopped)
+    }
+
+    /// Blocks until `condition` no longer holds for the core, and returns the locked core.
+    pub(crate) fn wait_while<F: FnMut(&mut SocketCore) -> bool>(
+        &self,
+        condition: F,
+    ) -> LrdpResult<MutexGuard<'_, SocketCore>> {
+        let core = self.lock()?;
+        self.state_changed
+            .wait_while(core, condition)
+            .map_err(|_| LrdpError::SocketStopped)
+    }
+
+    /// Returns `LrdpError::SocketStopped` if the socket has stopped, or is about to.
+    fn check_running(&self) -> LrdpResult<()> {
+        if self.is_stopping() {
+            return Err(LrdpError::SocketStopped);
+        }
+        Ok(())
+    }
+
+    /// Acts on the output of the locked `core`.
+    fn flush(&self, core: &mut SocketCore, out: Output) -> io::Result<()> {
+        flush(
+            &*self.transport,
+            core,
+            out,
+            &self.data_tx,
+            &self.receipt_txs,
+            &self.state_changed,
+            &self.timer_tx,
+        )
+    }
+
+    /// Opens a connection with the peer at `address`, and blocks until the peer has acknowledged
+    /// it.
+    pub(crate) fn open_to(&self, address: SocketAddr) -> LrdpResult<()> {
+        self.check_running()?;
+        let mut core = self.lock()?;
+        core.open(address)?;
+        self.flush(&mut core, Output::default())?;
+
+        // wait for the SYN-ACK.
+        let (mut core, _) = self
+            .state_changed
+            .wait_timeout_while(core, self.config.handshake_timeout, |core| {
+                core.state(address) == Some(ConnectionState::Opening)
+            })
+            .map_err(|_| LrdpError::SocketStopped)?;
+        match core.state(address) {
+            Some(ConnectionState::Opening) => {
+                core.remove(address);
+                Err(io::Error::new(io::ErrorKind::TimedOut, "Peer did not acknowledge SYN").into())
+            }
+            Some(ConnectionState::Unreachable) => {
+                core.remove(address);
+                Err(LrdpError::PeerUnreachable)
+            }
+            Some(_) => Ok(()),
+            None => Err(LrdpError::PeerClosed),
+        }
+    }
+
+    /// Closes the connection with the peer at `address`, and blocks until the peer has
+    /// acknowledged it.
+    pub(crate) fn close_to(&self, address: SocketAddr) -> LrdpResult<()> {
+        self.check_running()?;
+        let mut core = self.lock()?;
+        core.close(address);
+        self.flush(&mut core, Output::default())?;
+
+        // wait for the FIN-ACK.
+        let (mut core, _) = self
+            .state_changed
+            .wait_timeout_while(core, self.config.handshake_timeout, |core| {
+                core.state(address)
+                    .is_some_and(|state| state != ConnectionState::Unreachable)
+            })
+            .map_err(|_| LrdpError::SocketStopped)?;
+        match core.remove(address) {
+            Some(ConnectionState::Unreachable) => Err(LrdpError::PeerUnreachable),
+            Some(_) => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "Peer did not acknowledge FIN").into())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Starts closing the connection with the peer at `address`, without waiting for the peer to
+    /// acknowledge it.
+    pub(crate) fn start_close(&self, address: SocketAddr) -> LrdpResult<()> {
+        self.check_running()?;
+        let mut core = self.lock()?;
+        core.close(address);
+        self.flush(&mut core, Output::default())?;
+
+        Ok(())
+    }
+
+    /// Sends `data` reliably to `address` according to `options`, blocking until there is space in
+    /// its send queue.
+    pub(crate) fn send_to(
+        &self,
+        address: SocketAddr,
+        data: &[u8],
+        options: SendOptions,
+    ) -> LrdpResult<DeliveryReceipt> {
+        self.check_running()?;
+        // stopping the socket wakes up anyone who is waiting for space.
+        let core = self.wait_while(|core| core.is_full(address) && !self.is_stopping())?;
+        self.check_running()?;
+        self.send_locked(core, address, data, options)
+    }
+
+    /// Sends `data` reliably to `address` without blocking.
+    pub(crate) fn try_send_to(
+        &self,
+        address: SocketAddr,
+        data: &[u8],
+    ) -> LrdpResult<DeliveryReceipt> {
+        self.check_running()?;
+        let core = self.lock()?;
+        self.send_locked(core, address, data, SendOptions::default())
+    }
+
+    /// Queues `data` for `address` on the locked `core`, and returns a receipt for it.
+    fn send_locked(
+        &self,
+        mut core: MutexGuard<'_, SocketCore>,
+        address: SocketAddr,
+        data: &[u8],
+        options: SendOptions,
+    ) -> LrdpResult<DeliveryReceipt> {
+        let id = core.send(address, data, options, Instant::now())?;
+        core.track(id, address);
+        let flushed = self.flush(&mut core, Output::default());
+        drop(core);
+        let receipt = DeliveryReceipt {
+            id,
+            core: self.core.clone(),
+            state_changed: self.state_changed.clone(),
+            stopping: self.stopping.clone(),
+        };
+        flushed?;
+
+        Ok(receipt)
+    }
+
+    /// Returns a channel which is told about every message that is acknowledged by its peer or
+    /// given up on from now on.
+    pub(crate) fn receipts(&self) -> Receiver<DeliveryReport> {
+        let (receipt_tx, receipt_rx) = mpsc::channel();
+        if let Ok(mut receipt_txs) = self.receipt_txs.lock() {
+            receipt_txs.push(receipt_tx);
+        }
+        receipt_rx
+    }
+
+    /// Sends `data` to `addr` without any delivery guarantees.
+    pub(crate) fn send_unreliable_to<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+        data: &[u8],
+    ) -> LrdpResult<()> {
+        let address = resolve(addr)?;
+        self.lock()?
+            .send_unreliable(address, data, |buf, addr| self.transport.send_to(buf, addr))?;
+
+        Ok(())
+    }
+
+    /// Returns the error for an empty buffer which was received from `peer`, if it means that the
+    /// connection with the peer has ended.
+    pub(crate) fn connection_lost(&self, peer: SocketAddr) -> Option<LrdpError> {
+        match self.lock() {
+            Ok(core) => core.connection_lost(peer),
+            Err(e) => Some(e),
+        }
+    }
+
+    /// Returns the address that the socket is bound to.
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.transport.local_addr()
+    }
+
+    /// Emits an empty buffer from the socket's own address, so that whatever is waiting for data
+    /// from the socket wakes up and can find out that it has stopped.
+    pub(crate) fn wake_receiver(&self) {
+        if let Ok(addr) = self.local_addr() {
+            let _ = self.data_tx.send((Vec::new(), addr));
+        }
+    }
+
+    /// Whether the socket has stopped, or is about to.
+    pub(crate) fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::Relaxed)
+    }
+
+    /// Stops the socket once all of the queued data has been acknowledged or the configured
+    /// `stop_timeout` has passed, and returns the messages which were never acknowledged.
+    pub(crate) fn stop(&self) -> Vec<AddressedBuffer> {
+        self.shutdown(false, self.config.stop_timeout)
+    }
+
+    /// Waits until all of the queued data has been acknowledged or `timeout` has passed, then
+    /// stops the socket's threads and waits for them to finish. If `close` is set, the connections
+    /// with every peer are closed first, and the socket waits for them to be closed too. Returns
+    /// the messages which were never acknowledged, along with the peer they were for. If the socket
+    /// has already stopped there is nothing to do.
+    pub(crate) fn shutdown(&self, close: bool, timeout: Duration) -> Vec<AddressedBuffer> {
+        let threads = match self.threads.lock() {
+            Ok(mut threads) => std::mem::take(&mut *threads),
+            Err(_) => Vec::new(),
+        };
+        if threads.is_empty() {
+            return Vec::new();
+        }
+        let this_addr = self
+            .local_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+        log::info!(target: &this_addr, "Stopping socket...");
+
+        let undelivered = match self.drain(close, timeout) {
+            Ok(undelivered) => undelivered,
+            Err(e) => {
+                log::warn!(target: &this_addr, "Could not flush queued data: {}", e);
+                Vec::new()
+            }
+        };
+        if !undelivered.is_empty() {
+            log::warn!(
+                target: &this_addr,
+                "Giving up on {} undelivered messages.",
+                undelivered.len()
+            );
+        }
+
+        {
+            // the core is held so that a receipt which is about to wait cannot miss being woken.
+            let _core = self.core.lock();
+            self.stopping.store(true, Ordering::Relaxed);
+        }
+        self.state_changed.notify_all();
+        if let Err(e) = self.transport.wake() {
+            log::warn!(target: &this_addr, "Could not wake up the UDP reader: {}", e);
+        }
+        let _ = self.reader_tx.send(None);
+        let _ = self.timer_tx.send(TimerMessage::Stop);
+        for thread in threads {
+            if let Ok(Err(e)) = thread.join() {
+                log::warn!(target: &this_addr, "Socket thread failed: {}", e);
+            }
+        }
+        log::info!(target: &this_addr, "Socket stopped.");
+        undelivered
+    }
+
+    /// Waits until all of the queued data has been acknowledged or `timeout` has passed, closing
+    /// every connection first if `close` is set, and takes whatever is left in the send queues.
+    fn drain(&self, close: bool, timeout: Duration) -> LrdpResult<Vec<AddressedBuffer>> {
+        let mut core = self.lock()?;
+        if close {
+            core.close_all();
+            self.flush(&mut core, Output::default())?;
+        }
+        let (mut core, _) = self
+            .state_changed
+            .wait_timeout_while(core, timeout, |core| {
+                if close {
+                    core.has_connections()
+                } else {
+                    core.has_queued_data()
+                }
+            })
+            .map_err(|_| LrdpError::SocketStopped)?;
+        let undelivered = core.take_undelivered();
+        self.flush(&mut core, Output::default())?;
+        Ok(undelivered)
+    }
+}
+
+/// Follows a message which was sent reliably, to find out when its peer acknowledges it. Dropping
+/// the receipt does not affect the message.
+pub struct DeliveryReceipt {
+    id: MessageId,
+    core: Shared<SocketCore>,
+    state_changed: Arc<Condvar>,
+    stopping: Arc<AtomicBool>,
+}
+
+impl DeliveryReceipt {
+    /// Returns the id of the message, which is also used in the reports from
+    /// `LrdpSocket::receipts`.
+    pub fn id(&self) -> MessageId {
+        self.id
+    }
+
+    /// Returns what became of the message, or `None` if the peer has not acknowledged it yet.
+    pub fn poll(&self) -> LrdpResult<Option<Delivery>> {
+        let core = self.core.lock().map_err(|_| LrdpError::SocketStopped)?;
+        Ok(core.delivery(self.id))
+    }
+
+    /// Blocks until the peer acknowledges the message or it is given up on. If the socket stops
+    /// while this is waiting, `LrdpError::SocketStopped` is returned.
+    pub fn wait(&self) -> LrdpResult<Delivery> {
+        let core = self.core.lock().map_err(|_| LrdpError::SocketStopped)?;
+        let core = self
+            .state_changed
+            .wait_while(core, |core| self.is_pending(core))
+            .map_err(|_| LrdpError::SocketStopped)?;
+        core.delivery(self.id).ok_or(LrdpError::SocketStopped)
+    }
+
+    /// Blocks until the peer acknowledges the message or it is given up on, like `wait`, but
+    /// returns `None` if neither has happened after `timeout`. If the socket stops while this is
+    /// waiting, `LrdpError::SocketStopped` is returned.
+    pub fn wait_timeout(&self, timeout: Duration) -> LrdpResult<Option<Delivery>> {
+        let core = self.core.lock().map_err(|_| LrdpError::SocketStopped)?;
+        let (core, _) = self
+            .state_changed
+            .wait_timeout_while(core, timeout, |core| self.is_pending(core))
+            .map_err(|_| LrdpError::SocketStopped)?;
+        match core.delivery(self.id) {
+            Some(delivery) => Ok(Some(delivery)),
+            None if self.stopping.load(Ordering::Relaxed) => Err(LrdpError::SocketStopped),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether the message is still waiting to be acknowledged by a socket which is running.
+    fn is_pending(&self, core: &mut SocketCore) -> bool {
+        core.delivery(self.id).is_none() && !self.stopping.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for DeliveryReceipt {
+    fn drop(&mut self) {
+        if let Ok(mut core) = self.core.lock() {
+            core.untrack(self.id);
+        }
+    }
+}
+
+/// A socket which sends and receives data reliably over LRDP. Datagrams are exchanged over a UDP
+/// socket by default, but any `DatagramTransport` can be used instead.
+pub struct LrdpSocket<T: DatagramTransport = UdpSocket> {
+    handle: SocketHandle<T>,
     data_rx: Receiver<AddressedBuffer>,
+    /// Whether the handle has been handed over to something else, which is then responsible for
+    /// stopping the socket.
+    detached: bool,
 }
 
 impl LrdpSocket {
-    pub fn bind<A: ToSocketAddrs>(addrs: A) -> std::io::Result<Self> {
-        let udp_socket = UdpSocket::bind(addrs)?;
-        let clients: Shared<HashMap<SocketAddr, ClientState>> = shared(HashMap::new());
+    /// Creates an LRDP socket bound to `addrs` which uses the default configuration.
+    pub fn bind<A: ToSocketAddrs>(addrs: A) -> LrdpResult<Self> {
+        Self::bind_with_config(addrs, LrdpConfig::default())
+    }
+
+    /// Creates an LRDP socket bound to `addrs` which uses the given `config`.
+    pub fn bind_with_config<A: ToSocketAddrs>(addrs: A, config: LrdpConfig) -> LrdpResult<Self> {
+        Self::with_transport(UdpSocket::bind(addrs)?, config)
+    }
+}
+
+impl<T: DatagramTransport> LrdpSocket<T> {
+    /// Creates an LRDP socket which exchanges datagrams over `transport`, and uses the given
+    /// `config`.
+    pub fn with_transport(transport: T, config: LrdpConfig) -> LrdpResult<Self> {
+        let transport = Arc::new(transport);
+        let local_addr = transport.local_addr()?;
+        let core = shared(SocketCore::new(local_addr, config));
+        let state_changed = Arc::new(Condvar::new());
 
         // set up channel for emitting data.
         let (data_tx, data_rx) = mpsc::channel::<AddressedBuffer>();
+        let sender_data_tx = data_tx.clone();
+        let reader_data_tx = data_tx.clone();
+
+        // set up the list of channels which deliveries are reported on.
+        let receipt_txs = shared(Vec::new());
+        let reader_receipt_txs = receipt_txs.clone();
+        let sender_receipt_txs = receipt_txs.clone();
+
+        // set up channel for handing datagrams to the reader thread and stopping it. it never
+        // holds more than every buffer plus the message to stop.
+        let (reader_tx, reader_rx) = mpsc::sync_channel::<Option<Datagram>>(READ_BUFFERS + 1);
+
+        // set up channel for handing buffers back to the UDP reader thread, which starts out with
+        // every buffer that it will ever use.
+        let (recycle_tx, recycle_rx) = mpsc::channel::<Vec<u8>>();
+        for _ in 0..READ_BUFFERS {
+            let _ = recycle_tx.send(vec![0u8; u16::MAX as usize]);
+        }
 
-        // set up channel for stopping the reader thread.
-        let (reader_tx, reader_rx) = mpsc::channel::<Option<AddressedBuffer>>();
+        // set up channel for scheduling timers and stopping the timer thread.
+        let (timer_tx, timer_rx) = mpsc::channel::<TimerMessage>();
+        let reader_timer_tx = timer_tx.clone();
+        let sender_timer_tx = timer_tx.clone();
 
         // start reading things from the socket. this thread just pulls data from the socket and
-        // forwards it to the reader thread via the reader channel.
-        let udp_reader_socket = udp_socket.try_clone()?;
+        // forwards it to the reader thread via the reader channel, in buffers which the reader
+        // thread hands back once it is done with them. if the reader thread falls behind, this
+        // waits for a buffer to be handed back rather than allocating another.
+        // when the socket stops, the transport is woken up so that the reader finds out.
+        let udp_reader_socket = transport.clone();
         let udp_reader = reader_tx.clone();
-        thread::spawn(move || {
-            let mut buf = [0u8; std::u16::MAX as usize];
-            let this_addr = udp_reader_socket.local_addr().unwrap().to_string();
-            loop {
+        let stopping = Arc::new(AtomicBool::new(false));
+        let reader_stopping = stopping.clone();
+        let this_addr = local_addr.to_string();
+        let udp_reader_thread = thread::spawn(move || -> ThreadResult {
+            // a buffer which nothing was received into is kept for the next datagram.
+            let mut spare = None;
+            // the reader thread has stopped once it no longer hands buffers back.
+            while let Ok(mut buf) = spare.take().map_or_else(|| recycle_rx.recv(), Ok) {
                 let result = match udp_reader_socket.recv_from(&mut buf) {
+                    Ok(_) if reader_stopping.load(Ordering::Relaxed) => break,
                     Ok((recv, addr)) => {
                         log::debug!(
                             target: &this_addr,
                             "Received UDP packet from {}",
                             addr.to_string()
                         );
-                        udp_reader.send(Some((buf[0..recv].to_vec(), addr)))
+                        udp_reader.send(Some((buf, recv, addr)))
+                    }
+                    // errors such as ICMP port unreachable messages are caused by the network, so
+                    // they shouldn't stop the socket, but anything else means it is broken.
+                    Err(e) if datagram_transport::is_transient(&e) => {
+                        log::warn!(target: &this_addr, "Error receiving UDP packet: {}", e);
+                        spare = Some(buf);
+                        continue;
+                    }
+                    Err(e) => {
+                        log::error!(target: &this_addr, "UDP socket failed: {}", e);
+                        return Err(e.into());
                     }
-                    _ => udp_reader.send(None),
                 };
                 if result.is_err() {
                     log::warn!(
@@ -75,213 +583,415 @@ impl LrdpSocket {
                 }
             }
             log::trace!(target: &this_addr, "udp_reader thread at end.");
+            Ok(())
         });
 
-        // set up the reader thread. This thread does the bulk of the processing work when a packet
-        // is received.
-        let reader_clients = clients.clone();
-        let reader_socket = udp_socket.try_clone()?;
-        thread::spawn(move || -> ThreadResult {
-            let this_addr = reader_socket.local_addr().unwrap().to_string();
+        // set up the reader thread. This thread hands each packet to the core, which does the bulk
+        // of the processing work when a packet is received.
+        let reader_core = core.clone();
+        let reader_state_changed = state_changed.clone();
+        let reader_socket = transport.clone();
+        let reader_thread = thread::spawn(move || -> ThreadResult {
+            let this_addr = local_addr.to_string();
             loop {
-                let read_result = reader_rx.recv()?;
-                if read_result.is_none() {
-                    log::warn!(
-                        target: &this_addr,
-                        "Got empty read result. Shutting down reader thread."
-                    );
-                    break;
-                }
-                let (buf, addr) = read_result.unwrap();
-
-                // check if we know about this client yet.
-                if !reader_clients.lock().unwrap().contains_key(&addr) {
-                    log::info!(
-                        target: &this_addr,
-                        "... Adding new client {} from received data.",
-                        addr.to_string()
-                    );
-                    // if not, create a new state for it.
-                    let new_state = ClientState::new(addr);
-                    reader_clients.lock().unwrap().insert(addr, new_state);
-                }
-
-                // if no data was received then this is a "closing" packet, so we can drop this client.
-                if buf.len() == 0 {
-                    log::info!(
-                        target: &this_addr,
-                        "... Client {} sent closing packet.",
-                        addr.to_string()
-                    );
-                    reader_clients.lock().unwrap().remove(&addr);
-                }
-
-                log::debug!(
-                    target: &this_addr,
-                    "... Creating packet from header {:08b}",
-                    buf[0]
-                );
-                let packet = LrdpPacket::from_buffer(&buf);
-
-                // check if this packet is ACKing anything.
-                if packet.has_ack() {
-                    log::info!(
-                        target: &this_addr,
-                        "... ACK flag was set: {}",
-                        packet.ack_num()
-                    );
-                    let mut clients = reader_clients.lock().unwrap();
-
-                    let ack_result = clients.get_mut(&addr).unwrap().ack(packet.ack_num());
-                    // log if there was a bad value but don't do anything. This can happen in some
-                    // cases where two acks for the same packet are sent because of high latency on
-                    // the network.
-                    if let Err(ClientError::WrongAck(_)) = ack_result {
+                let (buf, recv, addr) = match reader_rx.recv()? {
+                    Some(read_result) => read_result,
+                    None => {
                         log::warn!(
                             target: &this_addr,
-                            "... WrongAck {}. Ignoring.",
-                            packet.ack_num()
+                            "Got empty read result. Shutting down reader thread."
                         );
+                        break;
                     }
-                }
+                };
 
-                // check for any data.
-                if packet.has_data() {
-                    log::info!(
-                        target: &this_addr,
-                        "... DATA flag was set: {}",
-                        packet.seq_num()
-                    );
-                    // check if the received sequence number is the expected one.
-                    let mut clients = reader_clients.lock().unwrap();
-                    let client = clients.get_mut(&addr).unwrap();
-
-                    match client.recv(packet.seq_num()) {
-                        Ok(_) => {
-                            log::info!(target: &this_addr, "... Seq number OK, emitting data.");
-                            // emit data. Don't really care about the result.
-                            let _ = data_tx.send((packet.data().to_vec(), addr));
-                            // ack the data.
-                            let ack_packet =
-                                LrdpPacket::create(Box::new([]), Some(packet.seq_num()), None);
-                            reader_socket.send_to(ack_packet.as_buffer().as_slice(), addr)?;
-                        }
-                        // if the seq number is wrong then correct the sender.
-                        Err(ClientError::WrongSeq(_, expected)) => {
-                            log::warn!(
-                                target: &this_addr,
-                                "... Expected seq num {} but got {}, sending ack.",
-                                expected,
-                                packet.seq_num()
-                            );
-                            // send ack with expected number.
-                            let ack_packet = LrdpPacket::create(Box::new([]), Some(expected), None);
-                            reader_socket.send_to(ack_packet.as_buffer().as_slice(), addr)?;
-                        }
-                        // for any other error just drop this client.
-                        Err(_) => {
-                            log::error!(
-                                target: &this_addr,
-                                "... Other error occurred. Dropping client {}",
-                                addr.to_string()
-                            );
-                            reader_clients.lock().unwrap().remove(&addr);
-                        }
+                let mut out = Output::default();
+                // the core is only poisoned if another thread panicked while holding it.
+                let mut core = match reader_core.lock() {
+                    Ok(core) => core,
+                    Err(_) => {
+                        log::error!(target: &this_addr, "Socket core is poisoned.");
+                        break;
                     }
+                };
+                core.handle_datagram(&buf[0..recv], addr, Instant::now(), &mut out);
+                let _ = recycle_tx.send(buf);
+                let flushed = flush(
+                    &*reader_socket,
+                    &mut core,
+                    out,
+                    &reader_data_tx,
+                    &reader_receipt_txs,
+                    &reader_state_changed,
+                    &reader_timer_tx,
+                );
+                if let Err(e) = flushed {
+                    log::warn!(target: &this_addr, "Error sending UDP packet: {}", e);
                 }
             }
             log::trace!(target: &this_addr, "reader thread at end.");
             Ok(())
         });
 
-        let (sender_tx, sender_rx) = mpsc::channel::<()>();
-        // ack thread.
-        let sender_clients = clients.clone();
-        let sender_socket = udp_socket.try_clone()?;
-        thread::spawn(move || -> ThreadResult {
-            let this_addr = sender_socket.local_addr().unwrap().to_string();
+        // timer thread. This sleeps until the earliest deadline of any client, or until a new
+        // deadline is scheduled, so that idle sockets do no work.
+        let sender_core = core.clone();
+        let sender_state_changed = state_changed.clone();
+        let sender_socket = transport.clone();
+        let timer_thread = thread::spawn(move || -> ThreadResult {
+            let this_addr = local_addr.to_string();
+            let mut timers = TimerQueue::new();
             loop {
-                match sender_rx.recv_timeout(Duration::from_millis(10)) {
+                let message = match timers.next_deadline() {
+                    Some(deadline) => {
+                        timer_rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    }
+                    None => timer_rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match message {
+                    Ok(TimerMessage::Schedule(addr, deadline)) => {
+                        timers.schedule(addr, deadline);
+                        continue;
+                    }
+                    Ok(TimerMessage::Stop) | Err(RecvTimeoutError::Disconnected) => break,
                     Err(RecvTimeoutError::Timeout) => {}
-                    _ => break,
                 }
-                // go through each client and check if any packets need to be retransmitted.
-                let mut clients = sender_clients.lock().unwrap();
-                for (addr, client) in clients.iter_mut() {
-                    if client.last_send.map_or(false, |last_send| {
-                        Instant::now().duration_since(last_send).as_millis() >= RESEND_DELAY
-                    }) {
-                        // resend last packet.
-                        if let Some(packet) = client.next_packet() {
-                            log::warn!(
-                                target: &this_addr,
-                                "Packet with seq {} was last sent more than {}ms ago. Sending again",
-                                packet.seq_num(),
-                                RESEND_DELAY
-                            );
-                            sender_socket
-                                .send_to(packet.as_buffer().as_slice(), addr)
-                                .unwrap();
-                            client.last_send = Some(Instant::now());
-                        }
+
+                let now = Instant::now();
+                let mut core = match sender_core.lock() {
+                    Ok(core) => core,
+                    Err(_) => {
+                        log::error!(target: &this_addr, "Socket core is poisoned.");
+                        break;
                     }
+                };
+                while let Some(addr) = timers.pop_expired(now) {
+                    core.handle_timeout(addr, now);
+                }
+                let flushed = flush(
+                    &*sender_socket,
+                    &mut core,
+                    Output::default(),
+                    &sender_data_tx,
+                    &sender_receipt_txs,
+                    &sender_state_changed,
+                    &sender_timer_tx,
+                );
+                if let Err(e) = flushed {
+                    log::warn!(target: &this_addr, "Error sending UDP packet: {}", e);
                 }
             }
-            log::trace!(target: &this_addr, "sender thread at end.");
+            log::trace!(target: &this_addr, "timer thread at end.");
             Ok(())
         });
 
         Ok(Self {
-            sender_tx,
-            reader_tx,
-            udp_socket,
-            clients,
+            handle: SocketHandle {
+                timer_tx,
+                reader_tx,
+                transport,
+                core,
+                state_changed,
+                data_tx,
+                receipt_txs,
+                config,
+                threads: shared(vec![udp_reader_thread, reader_thread, timer_thread]),
+                stopping,
+            },
             data_rx,
+            detached: false,
+        })
+    }
+
+    /// Opens a connection with the peer at `addr`, and blocks until the peer has acknowledged it.
+    /// If there is already a connection with the peer, this returns straight away.
+    pub fn open_to<A: ToSocketAddrs>(&mut self, addr: A) -> LrdpResult<()> {
+        self.handle.open_to(resolve(addr)?)
+    }
+
+    /// Closes the connection with the peer at `addr`, and blocks until the peer has acknowledged
+    /// it. Any data which is queued for the peer is delivered before the connection is closed. If
+    /// there is no connection with the peer, this returns straight away.
+    pub fn close_to<A: ToSocketAddrs>(&mut self, addr: A) -> LrdpResult<()> {
+        self.handle.close_to(resolve(addr)?)
+    }
+
+    /// Connects the socket to the peer at `addr`, opening a connection with it first if there is
+    /// not one already. Once connected, `send` and `recv` talk to the peer without naming it, and
+    /// datagrams from any other address are ignored.
+    pub fn connect<A: ToSocketAddrs>(&mut self, addr: A) -> LrdpResult<()> {
+        let address = resolve(addr)?;
+        self.handle.open_to(address)?;
+        self.handle.lock()?.connect(address);
+
+        Ok(())
+    }
+
+    /// Returns the address of the peer which the socket is connected to. If the socket is not
+    /// connected, an I/O error of the `NotConnected` kind is returned.
+    pub fn peer_addr(&self) -> LrdpResult<SocketAddr> {
+        self.handle.lock()?.peer().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "Socket is not connected").into()
         })
     }
 
+    /// Sends `data` reliably to the connected peer, like `send_to`.
+    pub fn send(&mut self, data: &[u8]) -> LrdpResult<DeliveryReceipt> {
+        self.send_with(data, SendOptions::default())
+    }
+
+    /// Sends `data` to the connected peer, like `send_to_with`.
+    pub fn send_with(&mut self, data: &[u8], options: SendOptions) -> LrdpResult<DeliveryReceipt> {
+        let peer = self.peer_addr()?;
+        self.handle.send_to(peer, data, options)
+    }
+
+    /// Receives data from the connected peer. When the peer closes the connection or is evicted
+    /// for being idle, `LrdpError::PeerClosed` is returned, and when it is declared unreachable,
+    /// `LrdpError::PeerUnreachable` is returned.
+    pub fn recv(&mut self) -> LrdpResult<Vec<u8>> {
+        let peer = self.peer_addr()?;
+        loop {
+            let (data, addr) = self.recv_from()?;
+            // anything from other peers was received before the socket was connected.
+            if addr != peer {
+                continue;
+            }
+            if data.is_empty() {
+                if let Some(e) = self.handle.connection_lost(peer) {
+                    return Err(e);
+                }
+            }
+            return Ok(data);
+        }
+    }
+
+    /// Sends `data` reliably to `addr`. If there is no connection with the peer yet, one is opened
+    /// first, and the data is sent once the peer has acknowledged it. If too many packets are
+    /// already waiting to be acknowledged by the peer, this blocks until there is space for
+    /// another. If the peer has been declared unreachable since the last call,
+    /// `LrdpError::PeerUnreachable` is returned instead.
+    ///
+    /// The returned receipt can be used to find out when the peer has acknowledged the data.
     pub fn send_to<A: ToSocketAddrs>(
         &mut self,
         addr: A,
         data: &[u8],
-    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
-        let mut addrs = addr.to_socket_addrs()?;
-        let address = addrs.next().unwrap();
-        let mut clients = self.clients.lock().unwrap();
-        // check if we know about this client yet.
-        if !clients.contains_key(&address) {
-            log::info!(
-                target: &self.udp_socket.local_addr().unwrap().to_string(),
-                "Adding new client {} from sent data.",
-                address.to_string()
-            );
-            // if not, create a new state for it.
-            let new_state = ClientState::new(address);
-            clients.insert(address, new_state);
+    ) -> LrdpResult<DeliveryReceipt> {
+        self.handle
+            .send_to(resolve(addr)?, data, SendOptions::default())
+    }
+
+    /// Sends `data` to `addr` like `send_to`, treating it according to `options`. If the message
+    /// has a time to live, it is given up on once that has passed, and its receipt reports it as
+    /// expired.
+    pub fn send_to_with<A: ToSocketAddrs>(
+        &mut self,
+        addr: A,
+        data: &[u8],
+        options: SendOptions,
+    ) -> LrdpResult<DeliveryReceipt> {
+        self.handle.send_to(resolve(addr)?, data, options)
+    }
+
+    /// Sends `data` reliably to `addr` like `send_to`, but never blocks. If too many packets are
+    /// already waiting to be acknowledged by the peer, `LrdpError::QueueFull` is returned and the
+    /// data should be sent again later.
+    pub fn try_send_to<A: ToSocketAddrs>(
+        &mut self,
+        addr: A,
+        data: &[u8],
+    ) -> LrdpResult<DeliveryReceipt> {
+        self.handle.try_send_to(resolve(addr)?, data)
+    }
+
+    /// Sends `data` to `addr` without any delivery guarantees. The packet is never queued or
+    /// retransmitted, and the receiver emits it without touching its sequence state.
+    pub fn send_unreliable_to<A: ToSocketAddrs>(&mut self, addr: A, data: &[u8]) -> LrdpResult<()> {
+        self.handle.send_unreliable_to(addr, data)
+    }
+
+    /// Returns a channel which is told about every message that any peer acknowledges from now on,
+    /// along with how long it took, and every message which is given up on. Each call returns a
+    /// new channel, and every channel hears about every message.
+    pub fn receipts(&self) -> Receiver<DeliveryReport> {
+        self.handle.receipts()
+    }
+
+    /// Returns the round trip time estimate for the peer at `addr`, or `None` if this socket does
+    /// not know about the peer.
+    pub fn peer_rtt<A: ToSocketAddrs>(&self, addr: A) -> Option<RttEstimator> {
+        let address = resolve(addr).ok()?;
+        self.handle.lock().ok()?.rtt(address)
+    }
+
+    /// Returns the traffic statistics of the connection with the peer at `addr`, or `None` if
+    /// there is no connection with it.
+    pub fn peer_stats<A: ToSocketAddrs>(&self, addr: A) -> Option<LrdpStats> {
+        let address = resolve(addr).ok()?;
+        self.handle.lock().ok()?.peer_stats(address)
+    }
+
+    /// Returns the traffic statistics of every peer which this socket has a connection with.
+    pub fn stats(&self) -> HashMap<SocketAddr, LrdpStats> {
+        self.handle
+            .lock()
+            .map(|core| core.stats())
+            .unwrap_or_default()
+    }
+
+    /// Returns the number of datagrams which this socket has dropped because they were not valid
+    /// LRDP packets.
+    pub fn malformed_datagrams(&self) -> u64 {
+        self.handle
+            .lock()
+            .map_or(0, |core| core.malformed_datagrams())
+    }
+
+    /// Returns the address that this socket is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.handle.local_addr()
+    }
+
+    /// Receives data from any peer. When a peer closes its connection, is evicted for being idle or
+    /// is declared unreachable, an empty buffer is received from it.
+    pub fn recv_from(&mut self) -> LrdpResult<AddressedBuffer> {
+        self.data_rx.recv().map_err(|_| LrdpError::SocketStopped)
+    }
+
+    /// Splits the socket into the handle which is used to talk to its peers, and the channel which
+    /// the data received from them is emitted on.
+    pub(crate) fn into_parts(mut self) -> (SocketHandle<T>, Receiver<AddressedBuffer>) {
+        self.detached = true;
+        let (_, data_rx) = mpsc::channel();
+        let data_rx = std::mem::replace(&mut self.data_rx, data_rx);
+        (self.handle.clone(), data_rx)
+    }
+
+    /// Stops the socket once all of the data which is queued for its peers has been acknowledged,
+    /// or the configured `stop_timeout` has passed. Returns the messages which were never
+    /// acknowledged, along with the peer they were for. The connections are not closed, so peers
+    /// only find out that the socket has gone once they time out.
+    pub fn stop(self) -> Vec<AddressedBuffer> {
+        self.handle.stop()
+    }
+
+    /// Closes the connections with every peer once all of the data which is queued for them has
+    /// been delivered, then stops the socket. Anything which has not been acknowledged after
+    /// `timeout` is given up on and returned, along with the peer it was for.
+    pub fn close_with_timeout(self, timeout: Duration) -> Vec<AddressedBuffer> {
+        self.handle.shutdown(true, timeout)
+    }
+}
+
+impl<T: DatagramTransport> Drop for LrdpSocket<T> {
+    /// Stops the socket like `stop` if that has not been done already.
+    fn drop(&mut self) {
+        if !self.detached {
+            self.handle.stop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::impaired_network::{ImpairedNetwork, NetworkProfile};
+
+    #[test]
+    fn stop_flushes_queued_data() {
+        let mut receiver = LrdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver_addr = receiver.local_addr().unwrap();
+        let mut sender = LrdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.connect(receiver_addr).unwrap();
+        for i in 0..10 {
+            sender.send(&[i]).unwrap();
         }
-        let mut client = clients.get_mut(&address).unwrap();
 
-        // queue the packet and send it.
-        let packet = LrdpPacket::create(data.into(), None, Some(client.next_seq_num()));
-        client.last_send = Some(Instant::now());
-        self.udp_socket
-            .send_to(packet.as_buffer().as_slice(), addr)
-            .unwrap();
-        client.enqueue(packet).unwrap();
+        // everything is delivered before the socket stops, which releases its address.
+        let sender_addr = sender.local_addr().unwrap();
+        assert!(sender.stop().is_empty());
+        UdpSocket::bind(sender_addr).unwrap();
+        for i in 0..10 {
+            assert_eq!(receiver.recv_from().unwrap(), (vec![i], sender_addr));
+        }
+        receiver.stop();
+    }
 
-        Ok(())
+    #[test]
+    fn stop_returns_undelivered_data() {
+        // nothing ever answers on this address.
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+        let config = LrdpConfig {
+            stop_timeout: Duration::from_millis(100),
+            ..LrdpConfig::default()
+        };
+        let mut socket = LrdpSocket::bind_with_config("127.0.0.1:0", config).unwrap();
+        socket.send_to(silent_addr, &[1, 2, 3]).unwrap();
+        let receipt = socket.send_to(silent_addr, &[4]).unwrap();
+        assert_eq!(receipt.poll().unwrap(), None);
+
+        assert_eq!(
+            socket.stop(),
+            vec![(vec![1, 2, 3], silent_addr), (vec![4], silent_addr)]
+        );
+        assert_eq!(receipt.wait().unwrap(), Delivery::Lost);
     }
 
-    pub fn recv_from(&mut self) -> Result<AddressedBuffer, RecvError> {
-        self.data_rx.recv()
+    #[test]
+    fn delivery_receipts() {
+        let mut receiver = LrdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver_addr = receiver.local_addr().unwrap();
+        let mut sender = LrdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.connect(receiver_addr).unwrap();
+        let receipts = sender.receipts();
+
+        let receipt = sender.send(&[1]).unwrap();
+        let delivery = receipt.wait().unwrap();
+        assert!(matches!(delivery, Delivery::Acknowledged(_)));
+        assert_eq!(
+            receipts.recv_timeout(Duration::from_secs(5)).unwrap(),
+            (receipt.id(), receiver_addr, delivery)
+        );
+        assert_eq!(receipt.poll().unwrap(), Some(delivery));
+        assert_eq!(receiver.recv_from().unwrap().0, vec![1]);
+        sender.stop();
+        receiver.stop();
+    }
+
+    #[test]
+    fn close_with_timeout_closes_connections() {
+        let mut receiver = LrdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver_addr = receiver.local_addr().unwrap();
+        let mut sender = LrdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.connect(receiver_addr).unwrap();
+        sender.send(&[1]).unwrap();
+
+        assert!(sender.close_with_timeout(Duration::from_secs(5)).is_empty());
+        assert_eq!(receiver.recv_from().unwrap().0, vec![1]);
+        assert_eq!(receiver.recv_from().unwrap().0, Vec::<u8>::new());
+        receiver.stop();
     }
 
-    pub fn stop(self) {
-        log::info!(target: &self.udp_socket.local_addr().unwrap().to_string(), "Stopping socket...");
-        // send stop messages over the channels.
-        self.reader_tx.send(None).unwrap();
-        self.sender_tx.send(()).unwrap();
-        // explicitly drop the data_rx channel so that listeners are disconnected from it.
-        drop(self.data_rx);
+    #[test]
+    fn impaired_transport() {
+        let network = ImpairedNetwork::new(NetworkProfile::DEGRADED, 1);
+        let mut receiver =
+            LrdpSocket::with_transport(network.bind(), LrdpConfig::default()).unwrap();
+        let receiver_addr = receiver.local_addr().unwrap();
+        let mut sender = LrdpSocket::with_transport(network.bind(), LrdpConfig::default()).unwrap();
+        sender.connect(receiver_addr).unwrap();
+        for i in 0..20 {
+            sender.send(&[i]).unwrap();
+        }
+
+        // everything gets through the lossy network in order.
+        let sender_addr = sender.local_addr().unwrap();
+        assert!(sender.stop().is_empty());
+        for i in 0..20 {
+            assert_eq!(receiver.recv_from().unwrap(), (vec![i], sender_addr));
+        }
+        receiver.stop();
     }
 }
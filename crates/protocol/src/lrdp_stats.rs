@@ -0,0 +1,71 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Counters describing the traffic exchanged with a single peer, so that the overhead of the
+/// protocol can be attributed to its causes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LrdpStats {
+    /// Reliable data packets which were sent for the first time.
+    pub data_packets_sent: u64,
+    /// Data and control packets which were sent again because they were not acknowledged in time.
+    pub retransmissions: u64,
+    /// Standalone ACK packets which were sent.
+    pub acks_sent: u64,
+    /// Acknowledgements which were piggybacked on outgoing data packets.
+    pub piggybacked_acks: u64,
+    /// Acknowledgements which were received, whether standalone or piggybacked.
+    pub acks_received: u64,
+    /// Standalone acknowledgements which repeated an earlier one, because the peer is missing a
+    /// packet.
+    pub duplicate_acks: u64,
+    /// Data packets which were received again after they had already been received.
+    pub duplicate_packets: u64,
+    /// Data packets which were received within the receive window, but ahead of a missing one.
+    pub out_of_order_packets: u64,
+    /// Acknowledgements of packets which were not in flight.
+    pub wrong_acks: u64,
+    /// Data packets which were outside of the receive window. Packets which were received and
+    /// acknowledged before are counted here as well as in `duplicate_packets`.
+    pub wrong_seqs: u64,
+    /// Bytes of every datagram sent to the peer, including headers, retransmissions,
+    /// acknowledgements and control packets.
+    pub wire_bytes_sent: u64,
+    /// Bytes of application data which were sent, counting each packet only once.
+    pub payload_bytes_sent: u64,
+    /// Bytes of every datagram received from the peer.
+    pub wire_bytes_received: u64,
+    /// Bytes of new application data which were received.
+    pub payload_bytes_received: u64,
+    /// Packets which are currently queued for the peer, including fragments waiting for room in
+    /// the send queue.
+    pub queue_depth: usize,
+    /// The current smoothed round trip time, if it has been measured.
+    pub srtt: Option<Duration>,
+}
+
+impl fmt::Display for LrdpStats {
+    /// Formats the counters as comma separated values, in the order of the fields. The round trip
+    /// time is given in microseconds, and as 0 if it has not been measured.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            self.data_packets_sent,
+            self.retransmissions,
+            self.acks_sent,
+            self.piggybacked_acks,
+            self.acks_received,
+            self.duplicate_acks,
+            self.duplicate_packets,
+            self.out_of_order_packets,
+            self.wrong_acks,
+            self.wrong_seqs,
+            self.wire_bytes_sent,
+            self.payload_bytes_sent,
+            self.wire_bytes_received,
+            self.payload_bytes_received,
+            self.queue_depth,
+            self.srtt.map_or(0, |srtt| srtt.as_micros()),
+        )
+    }
+}
@@ -0,0 +1,128 @@
+use std::time::Duration;
+
+/// The retransmission timeout which is used before any round trip times have been measured.
+const INITIAL_RTO: Duration = Duration::from_millis(300);
+/// The smallest retransmission timeout which can be used.
+const MIN_RTO: Duration = Duration::from_millis(25);
+/// The largest retransmission timeout which can be used, including any backoff.
+const MAX_RTO: Duration = Duration::from_secs(5);
+/// The largest number of times the retransmission timeout can be doubled.
+const MAX_BACKOFF: u32 = 8;
+
+/// Estimates the round trip time to a peer and derives a retransmission timeout from it. This
+/// follows the algorithm from RFC 6298.
+#[derive(Debug, Clone, Copy)]
+pub struct RttEstimator {
+    /// The smoothed round trip time, or `None` if no samples have been taken yet.
+    srtt: Option<Duration>,
+    /// The round trip time variation.
+    rttvar: Duration,
+    /// The number of consecutive timeouts since the last sample was taken.
+    backoff: u32,
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RttEstimator {
+    /// Creates a new estimator which has not taken any samples yet.
+    pub fn new() -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::from_millis(0),
+            backoff: 0,
+        }
+    }
+
+    /// Updates the estimate with a newly measured round trip time. Samples should only be taken
+    /// from packets which were not retransmitted, otherwise it is ambiguous which transmission was
+    /// acknowledged.
+    pub(crate) fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        self.backoff = 0;
+    }
+
+    /// Doubles the retransmission timeout after a packet was not acknowledged in time.
+    pub(crate) fn backoff(&mut self) {
+        self.backoff = (self.backoff + 1).min(MAX_BACKOFF);
+    }
+
+    /// The smoothed round trip time, or `None` if no samples have been taken yet.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// The round trip time variation.
+    pub fn rttvar(&self) -> Duration {
+        self.rttvar
+    }
+
+    /// The current retransmission timeout, including any backoff.
+    pub fn rto(&self) -> Duration {
+        let base = match self.srtt {
+            Some(srtt) => (srtt + self.rttvar * 4).max(MIN_RTO),
+            None => INITIAL_RTO,
+        };
+        (base * 2u32.pow(self.backoff)).min(MAX_RTO)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn initial_rto() {
+        let estimator = RttEstimator::new();
+        assert_eq!(estimator.srtt(), None);
+        assert_eq!(estimator.rto(), INITIAL_RTO);
+    }
+
+    #[test]
+    fn first_sample() {
+        let mut estimator = RttEstimator::new();
+        estimator.sample(Duration::from_millis(40));
+        assert_eq!(estimator.srtt(), Some(Duration::from_millis(40)));
+        assert_eq!(estimator.rttvar(), Duration::from_millis(20));
+        assert_eq!(estimator.rto(), Duration::from_millis(120));
+    }
+
+    #[test]
+    fn later_samples() {
+        let mut estimator = RttEstimator::new();
+        estimator.sample(Duration::from_millis(40));
+        estimator.sample(Duration::from_millis(80));
+        assert_eq!(estimator.srtt(), Some(Duration::from_millis(45)));
+        assert_eq!(estimator.rttvar(), Duration::from_millis(25));
+    }
+
+    #[test]
+    fn backoff() {
+        let mut estimator = RttEstimator::new();
+        estimator.sample(Duration::from_millis(40));
+        estimator.backoff();
+        estimator.backoff();
+        assert_eq!(estimator.rto(), Duration::from_millis(480));
+        for _ in 0..20 {
+            estimator.backoff();
+        }
+        assert_eq!(estimator.rto(), MAX_RTO);
+
+        // a new sample resets the backoff.
+        estimator.sample(Duration::from_millis(40));
+        assert!(estimator.rto() < Duration::from_millis(480));
+    }
+}
@@ -0,0 +1,755 @@
+use crate::client_state::ConnectionState;
+use crate::lrdp_config::LrdpConfig;
+use crate::lrdp_connection::{self, LrdpConnection, LrdpEvent};
+use crate::lrdp_delivery::{Delivery, DeliveryReport, MessageId, SendOptions};
+use crate::lrdp_error::{LrdpError, LrdpResult};
+use crate::lrdp_packet::{ControlType, HeaderFormat, LrdpPacketRef};
+use crate::lrdp_stats::LrdpStats;
+use crate::rtt_estimator::RttEstimator;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+/// A buffer of data which has an address associated with it.
+pub(crate) type AddressedBuffer = (Vec<u8>, SocketAddr);
+
+/// Everything which a socket needs to do after the core has handled something, besides sending
+/// the datagrams which come out of `SocketCore::poll_transmit`.
+#[derive(Debug, Default)]
+pub(crate) struct Output {
+    /// Data which needs to be emitted to the application. An empty buffer means that the peer has
+    /// gone away.
+    pub emitted: Vec<AddressedBuffer>,
+    /// Clients which need `handle_timeout` to be called at the given time.
+    pub timers: Vec<(SocketAddr, Instant)>,
+    /// Messages which have been acknowledged or given up on.
+    pub receipts: Vec<DeliveryReport>,
+    /// Whether the connection state of any client has changed, or space has been freed in its send
+    /// queue.
+    pub state_changed: bool,
+}
+
+/// The connections of an LRDP socket, without any I/O. The blocking and async sockets feed
+/// received datagrams and expired timers into the core, which hands them to the connection with
+/// the client they concern. They then send whatever `poll_transmit` writes to their send buffer
+/// until it returns `None`, and act on the `Output` it produces.
+pub(crate) struct SocketCore {
+    /// The local address of the socket, used as the log target.
+    this_addr: String,
+    config: LrdpConfig,
+    clients: HashMap<SocketAddr, LrdpConnection>,
+    /// Clients which were declared unreachable and have been forgotten about, until that has been
+    /// reported by `get_or_open` or `remove`, or the client opens a new connection.
+    unreachable: HashSet<SocketAddr>,
+    /// The number of datagrams which have been dropped because they were not valid LRDP packets.
+    malformed_datagrams: u64,
+    /// The only client which datagrams are accepted from, if the socket is connected.
+    peer: Option<SocketAddr>,
+    /// Whether new clients are queued up to be accepted by a listener.
+    listening: bool,
+    /// New clients which have opened a connection but have not been accepted yet.
+    accepted: VecDeque<SocketAddr>,
+    /// Messages which someone is waiting to hear about, along with the client they were sent to
+    /// and what became of them once that is known.
+    tracked: HashMap<MessageId, (SocketAddr, Option<Delivery>)>,
+    /// Clients whose connections have been handled since `poll_transmit` last ran out of their
+    /// datagrams, in the order they were handled.
+    driven: VecDeque<SocketAddr>,
+    /// Clients there is no connection with which are owed a FIN-ACK.
+    fin_acks: VecDeque<SocketAddr>,
+    /// The buffer which `transmit` writes datagrams to, which is reused for every datagram.
+    send_buf: Box<[u8]>,
+}
+
+/// Collects everything which happened on the `connection` with the client at `addr`, and schedules
+/// the next time the connection needs attention. This is done once the connection has run out of
+/// datagrams to transmit. When the connection with the client has been lost, an empty buffer is
+/// emitted to let the application know.
+fn collect(connection: &mut LrdpConnection, addr: SocketAddr, out: &mut Output) {
+    while let Some(event) = connection.poll_event() {
+        match event {
+            LrdpEvent::Message(data) => out.emitted.push((data, addr)),
+            LrdpEvent::PeerClosed | LrdpEvent::Idle | LrdpEvent::Unreachable => {
+                out.emitted.push((Vec::new(), addr));
+                out.state_changed = true;
+            }
+            LrdpEvent::Connected | LrdpEvent::Closed => out.state_changed = true,
+            LrdpEvent::Receipt(id, delivery) => out.receipts.push((id, addr, delivery)),
+        }
+    }
+    if let Some(deadline) = connection.poll_timeout() {
+        out.timers.push((addr, deadline));
+    }
+}
+
+/// Writes an unreliable datagram carrying `data` with the given header `format` to the start of
+/// `buf`, and returns its length. If it does not fit, an I/O error of the `InvalidInput` kind is
+/// returned.
+pub(crate) fn encode_unreliable(
+    data: &[u8],
+    format: HeaderFormat,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    let packet = LrdpPacketRef::unreliable(data);
+    if packet.encoded_len(format) > buf.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Data is too large for a datagram",
+        ));
+    }
+    Ok(packet.encode_into(format, buf))
+}
+
+impl SocketCore {
+    /// Creates a core for a socket bound to `this_addr` which has no clients yet.
+    pub(crate) fn new(this_addr: SocketAddr, config: LrdpConfig) -> Self {
+        Self {
+            this_addr: this_addr.to_string(),
+            config,
+            clients: HashMap::new(),
+            unreachable: HashSet::new(),
+            malformed_datagrams: 0,
+            peer: None,
+            listening: false,
+            accepted: VecDeque::new(),
+            tracked: HashMap::new(),
+            driven: VecDeque::new(),
+            fin_acks: VecDeque::new(),
+            send_buf: vec![0u8; u16::MAX as usize].into_boxed_slice(),
+        }
+    }
+
+    /// Starts queueing up clients which open a connection, so that they can be accepted.
+    pub(crate) fn listen(&mut self) {
+        self.listening = true;
+    }
+
+    /// Returns the next client which has opened a connection since it was last accepted.
+    pub(crate) fn accept(&mut self) -> Option<SocketAddr> {
+        self.accepted.pop_front()
+    }
+
+    /// Whether there are any clients waiting to be accepted.
+    pub(crate) fn has_accepted(&self) -> bool {
+        !self.accepted.is_empty()
+    }
+
+    /// Whether the client at `addr` has opened a connection and is waiting to be accepted.
+    pub(crate) fn is_waiting(&self, addr: SocketAddr) -> bool {
+        self.accepted.contains(&addr)
+    }
+
+    /// Connects to the client at `addr`, so that datagrams from any other address are ignored.
+    pub(crate) fn connect(&mut self, addr: SocketAddr) {
+        self.peer = Some(addr);
+    }
+
+    /// Returns the client which the socket is connected to, if there is one.
+    pub(crate) fn peer(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    /// Returns why the connection with the client at `addr` is gone, if it is. This is used to
+    /// tell whether an empty buffer which was emitted for the client means that the connection has
+    /// ended, or whether it was an empty message.
+    pub(crate) fn connection_lost(&self, addr: SocketAddr) -> Option<LrdpError> {
+        match self.state(addr) {
+            Some(ConnectionState::Unreachable) => Some(LrdpError::PeerUnreachable),
+            Some(_) => None,
+            None => Some(LrdpError::PeerClosed),
+        }
+    }
+
+    /// Returns the number of datagrams which have been dropped because they were not valid LRDP
+    /// packets.
+    pub(crate) fn malformed_datagrams(&self) -> u64 {
+        self.malformed_datagrams
+    }
+
+    /// Returns the state of the connection with the client at `addr`, or `None` if there is no
+    /// connection with the client.
+    pub(crate) fn state(&self, addr: SocketAddr) -> Option<ConnectionState> {
+        if self.unreachable.contains(&addr) {
+            return Some(ConnectionState::Unreachable);
+        }
+        self.clients.get(&addr).map(|client| client.state())
+    }
+
+    /// Forgets about the client at `addr`, and returns the state its connection was in.
+    pub(crate) fn remove(&mut self, addr: SocketAddr) -> Option<ConnectionState> {
+        if self.unreachable.remove(&addr) {
+            return Some(ConnectionState::Unreachable);
+        }
+        self.clients.remove(&addr).map(|client| client.state())
+    }
+
+    /// Whether the send queue for the client at `addr` is full.
+    pub(crate) fn is_full(&self, addr: SocketAddr) -> bool {
+        self.clients
+            .get(&addr)
+            .is_some_and(|client| client.is_full())
+    }
+
+    /// Returns the round trip time estimate for the client at `addr`.
+    pub(crate) fn rtt(&self, addr: SocketAddr) -> Option<RttEstimator> {
+        self.clients.get(&addr).map(|client| *client.rtt())
+    }
+
+    /// Returns the traffic statistics of every client which there is a connection with.
+    pub(crate) fn stats(&self) -> HashMap<SocketAddr, LrdpStats> {
+        self.clients
+            .iter()
+            .map(|(addr, client)| (*addr, client.stats()))
+            .collect()
+    }
+
+    /// Returns the traffic statistics of the client at `addr`, or `None` if there is no connection
+    /// with the client.
+    pub(crate) fn peer_stats(&self, addr: SocketAddr) -> Option<LrdpStats> {
+        self.clients.get(&addr).map(|client| client.stats())
+    }
+
+    /// Returns the connection with the client at `addr`. If there is no connection with the client
+    /// yet, a new one is started. If the client was declared unreachable, it is forgotten about
+    /// and an error is returned, so the next call will try to reconnect.
+    fn get_or_open(&mut self, addr: SocketAddr) -> LrdpResult<&mut LrdpConnection> {
+        if self.state(addr) == Some(ConnectionState::Unreachable) {
+            self.remove(addr);
+            return Err(LrdpError::PeerUnreachable);
+        }
+        let this_addr = &self.this_addr;
+        let config = self.config;
+        Ok(self.clients.entry(addr).or_insert_with(|| {
+            log::info!(
+                target: this_addr,
+                "Opening connection with new client {}.",
+                addr.to_string()
+            );
+            LrdpConnection::connect(addr, config)
+        }))
+    }
+
+    /// Marks the connection with the client at `addr` as having been handled, so that its
+    /// datagrams come out of the next `poll_transmit`.
+    fn drive(&mut self, addr: SocketAddr) {
+        if !self.driven.contains(&addr) {
+            self.driven.push_back(addr);
+        }
+    }
+
+    /// Writes the next datagram which needs to be sent at `now` to the start of `buf`, and returns
+    /// its length along with the client it is for, or `None` once there is nothing more to send.
+    /// Once a connection has run out of datagrams, everything else which happened on it is
+    /// collected in `out` like `collect`, and the client is forgotten about if the connection has
+    /// closed. This needs to be called until it returns `None` after anything has been handed to
+    /// the core. A `buf` of `u16::MAX` bytes can hold any datagram.
+    pub(crate) fn poll_transmit(
+        &mut self,
+        now: Instant,
+        buf: &mut [u8],
+        out: &mut Output,
+    ) -> Option<(usize, SocketAddr)> {
+        if let Some(addr) = self.fin_acks.pop_front() {
+            let fin_ack = LrdpPacketRef::control(ControlType::FinAck, &[]);
+            return Some((fin_ack.encode_into(self.config.header_format, buf), addr));
+        }
+        while let Some(&addr) = self.driven.front() {
+            if let Some(client) = self.clients.get_mut(&addr) {
+                if let Some(len) = client.poll_transmit(now, buf) {
+                    return Some((len, addr));
+                }
+            }
+            self.driven.pop_front();
+            self.finish(addr, out);
+        }
+        None
+    }
+
+    /// Sends every datagram which needs to be sent at `now` with `send`, like `poll_transmit` but
+    /// writing them to the core's own send buffer. If sending fails, the remaining datagrams are
+    /// left for the next call and the error is returned.
+    pub(crate) fn transmit<F>(
+        &mut self,
+        now: Instant,
+        out: &mut Output,
+        mut send: F,
+    ) -> io::Result<()>
+    where
+        F: FnMut(&[u8], SocketAddr) -> io::Result<usize>,
+    {
+        let mut buf = std::mem::take(&mut self.send_buf);
+        let mut result = Ok(());
+        while let Some((len, addr)) = self.poll_transmit(now, &mut buf, out) {
+            if let Err(e) = send(&buf[..len], addr) {
+                result = Err(e);
+                break;
+            }
+        }
+        self.send_buf = buf;
+        result
+    }
+
+    /// Sends `data` to `addr` with `send` as an unreliable datagram, which is written to the core's
+    /// send buffer. If it is too large for a datagram, an I/O error of the `InvalidInput` kind is
+    /// returned.
+    pub(crate) fn send_unreliable<F>(
+        &mut self,
+        addr: SocketAddr,
+        data: &[u8],
+        send: F,
+    ) -> io::Result<usize>
+    where
+        F: FnOnce(&[u8], SocketAddr) -> io::Result<usize>,
+    {
+        let len = encode_unreliable(data, self.config.header_format, &mut self.send_buf)?;
+        send(&self.send_buf[..len], addr)
+    }
+
+    /// Collects the output of the connection with the client at `addr` like `collect`, and forgets
+    /// about the client if the connection has closed.
+    fn finish(&mut self, addr: SocketAddr, out: &mut Output) {
+        let client = match self.clients.get_mut(&addr) {
+            Some(client) => client,
+            None => return,
+        };
+        let receipts = out.receipts.len();
+        collect(client, addr, out);
+        for (id, _, delivery) in out.receipts[receipts..].iter() {
+            if let Some((_, outcome)) = self.tracked.get_mut(id) {
+                *outcome = Some(*delivery);
+                out.state_changed = true;
+            }
+        }
+        match client.state() {
+            ConnectionState::Closed => {
+                log::info!(
+                    target: &self.this_addr,
+                    "... Connection with client {} closed.",
+                    addr.to_string()
+                );
+                self.clients.remove(&addr);
+                out.state_changed = true;
+            }
+            // only the fact that the client is unreachable needs to be kept until it is reported.
+            ConnectionState::Unreachable => {
+                self.clients.remove(&addr);
+                self.unreachable.insert(addr);
+                out.state_changed = true;
+            }
+            _ => {}
+        }
+    }
+
+    /// Starts opening a connection with the client at `addr`, if there is not one already.
+    pub(crate) fn open(&mut self, addr: SocketAddr) -> LrdpResult<()> {
+        self.get_or_open(addr)?;
+        self.drive(addr);
+        Ok(())
+    }
+
+    /// Starts closing the connection with the client at `addr`, if there is one.
+    pub(crate) fn close(&mut self, addr: SocketAddr) {
+        if let Some(client) = self.clients.get_mut(&addr) {
+            client.close();
+            self.drive(addr);
+        }
+    }
+
+    /// Starts closing the connections with every client.
+    pub(crate) fn close_all(&mut self) {
+        let addrs: Vec<SocketAddr> = self.clients.keys().copied().collect();
+        for addr in addrs {
+            self.close(addr);
+        }
+    }
+
+    /// Whether there are any connections which have not been closed or declared unreachable yet.
+    pub(crate) fn has_connections(&self) -> bool {
+        self.clients
+            .values()
+            .any(|client| client.state() != ConnectionState::Unreachable)
+    }
+
+    /// Whether any client has data queued which it has not acknowledged yet, or has not
+    /// acknowledged the FIN which closes its connection.
+    pub(crate) fn has_queued_data(&self) -> bool {
+        self.clients
+            .values()
+            .any(|client| client.has_queued_data() || client.state() == ConnectionState::Closing)
+    }
+
+    /// Discards everything which is queued for every client, and returns the messages which were
+    /// not acknowledged along with the address of the client they were for. The messages are
+    /// reported as lost by the next `poll_transmit`.
+    pub(crate) fn take_undelivered(&mut self) -> Vec<AddressedBuffer> {
+        let mut undelivered = Vec::new();
+        let addrs: Vec<SocketAddr> = self.clients.keys().copied().collect();
+        for addr in addrs {
+            if let Some(client) = self.clients.get_mut(&addr) {
+                for message in client.take_undelivered() {
+                    undelivered.push((message, addr));
+                }
+            }
+            self.drive(addr);
+        }
+        undelivered
+    }
+
+    /// Starts keeping track of what becomes of the message with the given `id`, which was sent to
+    /// the client at `addr`, so that it can be looked up with `delivery`.
+    pub(crate) fn track(&mut self, id: MessageId, addr: SocketAddr) {
+        self.tracked.insert(id, (addr, None));
+    }
+
+    /// Stops keeping track of the message with the given `id`.
+    pub(crate) fn untrack(&mut self, id: MessageId) {
+        self.tracked.remove(&id);
+    }
+
+    /// Returns what became of the tracked message with the given `id`, or `None` if the client has
+    /// not acknowledged it yet. A message which the client's connection no longer knows about
+    /// without having been reported was lost along with the connection.
+    pub(crate) fn delivery(&self, id: MessageId) -> Option<Delivery> {
+        let (addr, outcome) = self.tracked.get(&id)?;
+        outcome.or_else(|| {
+            let pending = self
+                .clients
+                .get(addr)
+                .is_some_and(|client| client.is_pending(id));
+            if pending {
+                None
+            } else {
+                Some(Delivery::Lost)
+            }
+        })
+    }
+
+    /// Queues `data` to be sent reliably to the client at `addr` as a single message, opening a
+    /// connection first if there is not one already. If the send queue for the client is full,
+    /// `LrdpError::QueueFull` is returned and nothing is queued. The message is treated according
+    /// to `options`.
+    pub(crate) fn send(
+        &mut self,
+        addr: SocketAddr,
+        data: &[u8],
+        options: SendOptions,
+        now: Instant,
+    ) -> LrdpResult<MessageId> {
+        // queue the message, and whatever is inside the send window is sent by `poll_transmit`.
+        let id = self.get_or_open(addr)?.send_with(data, options, now)?;
+        self.drive(addr);
+        Ok(id)
+    }
+
+    /// Handles a timer for the client at `addr` which expired at `now`. Idle clients are evicted,
+    /// clients which have not finished opening or closing the connection within the handshake
+    /// timeout are declared unreachable, and anything which has timed out is retransmitted by the
+    /// next `poll_transmit`.
+    pub(crate) fn handle_timeout(&mut self, addr: SocketAddr, now: Instant) {
+        // the client may have been removed since its timer was scheduled.
+        if let Some(client) = self.clients.get_mut(&addr) {
+            client.handle_timeout(now);
+            self.drive(addr);
+        }
+    }
+
+    /// Handles a datagram `buf` which was received from `addr` at `now`.
+    pub(crate) fn handle_datagram(
+        &mut self,
+        buf: &[u8],
+        addr: SocketAddr,
+        now: Instant,
+        out: &mut Output,
+    ) {
+        let this_addr = &self.this_addr;
+
+        if self.peer.is_some_and(|peer| peer != addr) {
+            log::debug!(
+                target: this_addr,
+                "... Not connected to {}, ignoring.",
+                addr.to_string()
+            );
+            return;
+        }
+
+        if let Some(client) = self.clients.get_mut(&addr) {
+            let queued = client.queued_len();
+            if client.handle_datagram(now, buf).is_err() {
+                self.malformed_datagrams += 1;
+                return;
+            }
+            // anyone waiting for space in the send queue can carry on.
+            if client.queued_len() < queued {
+                out.state_changed = true;
+            }
+            self.drive(addr);
+            return;
+        }
+
+        // only a few packets make sense from a client there is no connection with.
+        let packet = match LrdpPacketRef::parse(buf, self.config.header_format) {
+            Ok(packet) => packet,
+            Err(e) => {
+                log::warn!(
+                    target: this_addr,
+                    "... Client {} sent a malformed packet, ignoring: {}",
+                    addr.to_string(),
+                    e
+                );
+                self.malformed_datagrams += 1;
+                return;
+            }
+        };
+        match packet.control_type() {
+            // unreliable packets are emitted straight away and are never acknowledged.
+            None if packet.is_unreliable() => {
+                log::info!(target: this_addr, "... Unreliable packet, emitting data.");
+                out.emitted.push((packet.data().to_vec(), addr));
+            }
+            Some(ControlType::Syn) => {
+                // make sure both sides agree on the header format.
+                let syn_format = lrdp_connection::syn_format(&packet);
+                if syn_format != Some(self.config.header_format) {
+                    log::error!(
+                        target: this_addr,
+                        "... Client {} uses header format {:?}, ignoring SYN.",
+                        addr.to_string(),
+                        syn_format
+                    );
+                    return;
+                }
+                log::info!(
+                    target: this_addr,
+                    "... Adding new client {} from SYN.",
+                    addr.to_string()
+                );
+                let mut client = LrdpConnection::accept(addr, self.config);
+                let _ = client.handle_datagram(now, buf);
+                self.clients.insert(addr, client);
+                self.unreachable.remove(&addr);
+                if self.listening {
+                    self.accepted.push_back(addr);
+                }
+                self.drive(addr);
+            }
+            // the FIN-ACK for a FIN must have been lost, so send it again.
+            Some(ControlType::Fin) => self.fin_acks.push_back(addr),
+            _ => {
+                log::warn!(
+                    target: this_addr,
+                    "... Client {} has not opened a connection, ignoring.",
+                    addr.to_string()
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lrdp_packet::{LrdpPacket, SELECTIVE_ACK_OPTION};
+
+    /// Everything which a core produced, including the datagrams it had to send.
+    #[derive(Debug, Default)]
+    struct Polled {
+        out: Output,
+        transmits: Vec<AddressedBuffer>,
+    }
+
+    /// Collects every datagram which `core` has to send at `now`, along with the rest of `out`.
+    fn poll(core: &mut SocketCore, mut out: Output, now: Instant) -> Polled {
+        let mut buf = [0u8; u16::MAX as usize];
+        let mut transmits = Vec::new();
+        while let Some((len, addr)) = core.poll_transmit(now, &mut buf, &mut out) {
+            transmits.push((buf[..len].to_vec(), addr));
+        }
+        Polled { out, transmits }
+    }
+
+    /// Hands every datagram in `polled` which is addressed to `to` over to `core`, as if it was
+    /// sent by `from`. Returns whatever `core` produced in response.
+    fn deliver(polled: &Polled, from: SocketAddr, to: SocketAddr, core: &mut SocketCore) -> Polled {
+        let mut response = Output::default();
+        for (buf, addr) in polled.transmits.iter() {
+            if *addr == to {
+                core.handle_datagram(buf, from, Instant::now(), &mut response);
+            }
+        }
+        poll(core, response, Instant::now())
+    }
+
+    #[test]
+    fn open_send_and_close() {
+        let a_addr: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let b_addr: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        let mut a = SocketCore::new(a_addr, LrdpConfig::default());
+        let mut b = SocketCore::new(b_addr, LrdpConfig::default());
+
+        // the data waits for the handshake to finish.
+        let id = a
+            .send(b_addr, &[1, 2, 3], SendOptions::default(), Instant::now())
+            .unwrap();
+        a.track(id, b_addr);
+        assert_eq!(a.state(b_addr), Some(ConnectionState::Opening));
+        let syn = poll(&mut a, Output::default(), Instant::now());
+        assert_eq!(syn.transmits.len(), 1);
+        assert_eq!(syn.out.timers.len(), 1);
+
+        let syn_ack = deliver(&syn, a_addr, b_addr, &mut b);
+        assert_eq!(b.state(a_addr), Some(ConnectionState::Open));
+        let data = deliver(&syn_ack, b_addr, a_addr, &mut a);
+        assert_eq!(a.state(b_addr), Some(ConnectionState::Open));
+        let received = deliver(&data, a_addr, b_addr, &mut b);
+        assert_eq!(received.out.emitted, vec![(vec![1, 2, 3], a_addr)]);
+
+        // the ack is held back in case there is data to piggyback it on.
+        assert!(received.transmits.is_empty());
+        let ack_delay = LrdpConfig::default().ack_delay.unwrap();
+        let ack_due = Instant::now() + ack_delay;
+        b.handle_timeout(a_addr, ack_due);
+        let ack = poll(&mut b, Output::default(), ack_due);
+        assert_eq!(ack.transmits.len(), 1);
+        assert_eq!(a.delivery(id), None);
+        let acked = deliver(&ack, b_addr, a_addr, &mut a).out;
+        assert!(matches!(
+            acked.receipts[..],
+            [(acked_id, addr, Delivery::Acknowledged(_))] if acked_id == id && addr == b_addr
+        ));
+        assert_eq!(a.delivery(id), Some(acked.receipts[0].2));
+        assert!(acked.state_changed);
+
+        // every datagram which was exchanged is counted on both sides.
+        let a_stats = a.peer_stats(b_addr).unwrap();
+        let b_stats = b.peer_stats(a_addr).unwrap();
+        assert_eq!(a_stats.payload_bytes_sent, 3);
+        assert_eq!(b_stats.payload_bytes_received, 3);
+        assert_eq!(b_stats.acks_sent, 1);
+        assert_eq!(a_stats.wire_bytes_sent, b_stats.wire_bytes_received);
+        assert_eq!(b_stats.wire_bytes_sent, a_stats.wire_bytes_received);
+
+        // closing tells the peer's application that the connection is gone.
+        a.close(b_addr);
+        let fin = poll(&mut a, Output::default(), Instant::now());
+        let fin_ack = deliver(&fin, a_addr, b_addr, &mut b);
+        assert_eq!(fin_ack.out.emitted, vec![(Vec::new(), a_addr)]);
+        assert_eq!(b.state(a_addr), None);
+        deliver(&fin_ack, b_addr, a_addr, &mut a);
+        assert_eq!(a.state(b_addr), None);
+    }
+
+    #[test]
+    fn forgets_unreachable_clients() {
+        let config = LrdpConfig {
+            max_retransmissions: Some(0),
+            idle_timeout: None,
+            ..LrdpConfig::default()
+        };
+        let a_addr: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let b_addr: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        let mut a = SocketCore::new(a_addr, config);
+
+        // nothing ever answers the SYN.
+        a.open(b_addr).unwrap();
+        let mut now = Instant::now();
+        let mut polled = poll(&mut a, Output::default(), now);
+        while polled.out.emitted.is_empty() {
+            now = polled.out.timers.last().unwrap().1;
+            a.handle_timeout(b_addr, now);
+            polled = poll(&mut a, Output::default(), now);
+        }
+        assert_eq!(polled.out.emitted, vec![(Vec::new(), b_addr)]);
+
+        // only the fact that the client is unreachable is kept, until it has been reported.
+        assert!(a.clients.is_empty());
+        assert_eq!(a.state(b_addr), Some(ConnectionState::Unreachable));
+        assert!(matches!(
+            a.send(b_addr, &[1], SendOptions::default(), now),
+            Err(LrdpError::PeerUnreachable)
+        ));
+        assert_eq!(a.state(b_addr), None);
+    }
+
+    #[test]
+    fn negotiates_selective_ack() {
+        let a_addr: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let b_addr: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        let sack = LrdpConfig {
+            selective_ack: true,
+            ..LrdpConfig::default()
+        };
+        for (a_config, b_config, expected) in [
+            (sack, sack, SELECTIVE_ACK_OPTION),
+            (sack, LrdpConfig::default(), 0),
+            (LrdpConfig::default(), sack, 0),
+        ]
+        .iter()
+        {
+            let mut a = SocketCore::new(a_addr, *a_config);
+            let mut b = SocketCore::new(b_addr, *b_config);
+            a.open(b_addr).unwrap();
+            let syn = poll(&mut a, Output::default(), Instant::now());
+            let syn_ack = deliver(&syn, a_addr, b_addr, &mut b);
+            deliver(&syn_ack, b_addr, a_addr, &mut a);
+            assert_eq!(a.clients[&b_addr].options(), *expected);
+            assert_eq!(b.clients[&a_addr].options(), *expected);
+        }
+    }
+
+    #[test]
+    fn counts_malformed_datagrams() {
+        let a_addr: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let mut a = SocketCore::new(a_addr, LrdpConfig::default());
+        let mut out = Output::default();
+        let from = "127.0.0.1:2000".parse().unwrap();
+        for buf in [&[][..], &[0b00111111][..]].iter() {
+            a.handle_datagram(buf, from, Instant::now(), &mut out);
+        }
+        assert_eq!(a.malformed_datagrams(), 2);
+        let polled = poll(&mut a, out, Instant::now());
+        assert!(polled.transmits.is_empty());
+        assert!(polled.out.emitted.is_empty());
+    }
+
+    #[test]
+    fn connected_ignores_other_clients() {
+        let a_addr: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let b_addr: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        let c_addr: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        let mut a = SocketCore::new(a_addr, LrdpConfig::default());
+        a.connect(b_addr);
+        assert_eq!(a.peer(), Some(b_addr));
+
+        let packet = LrdpPacket::unreliable(Box::new([1]));
+        let buf = packet.as_buffer(HeaderFormat::Compact);
+        let mut out = Output::default();
+        a.handle_datagram(&buf, c_addr, Instant::now(), &mut out);
+        assert!(out.emitted.is_empty());
+        a.handle_datagram(&buf, b_addr, Instant::now(), &mut out);
+        assert_eq!(out.emitted, vec![(vec![1], b_addr)]);
+        assert!(matches!(
+            a.connection_lost(b_addr),
+            Some(LrdpError::PeerClosed)
+        ));
+    }
+
+    #[test]
+    fn ignores_data_from_unknown_clients() {
+        let a_addr: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let mut a = SocketCore::new(a_addr, LrdpConfig::default());
+        let packet = LrdpPacket::create(Box::new([1]), None, Some(0));
+        let mut out = Output::default();
+        let from = "127.0.0.1:2000".parse().unwrap();
+        a.handle_datagram(
+            &packet.as_buffer(HeaderFormat::Compact),
+            from,
+            Instant::now(),
+            &mut out,
+        );
+        let polled = poll(&mut a, out, Instant::now());
+        assert!(polled.transmits.is_empty());
+        assert!(polled.out.emitted.is_empty());
+    }
+}
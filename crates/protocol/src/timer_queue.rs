@@ -0,0 +1,108 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::hash::Hash;
+use std::time::Instant;
+
+/// A queue of deadlines, one per key, ordered so that the earliest deadline can be found quickly.
+/// Rescheduling a key does not remove its old entry from the heap; stale entries are skipped when
+/// they reach the front instead.
+#[derive(Debug)]
+pub(crate) struct TimerQueue<K> {
+    heap: BinaryHeap<Reverse<(Instant, K)>>,
+    /// The deadline which is currently scheduled for each key.
+    scheduled: HashMap<K, Instant>,
+}
+
+impl<K: Copy + Eq + Hash + Ord> TimerQueue<K> {
+    /// Creates an empty timer queue.
+    pub(crate) fn new() -> Self {
+        Self {
+            heap: BinaryHeap::new(),
+            scheduled: HashMap::new(),
+        }
+    }
+
+    /// Schedules `key` to expire at `deadline`. If the key is already scheduled to expire before
+    /// then, this does nothing. Whoever handles the key when it expires is expected to schedule it
+    /// again if it still needs a later deadline.
+    pub(crate) fn schedule(&mut self, key: K, deadline: Instant) {
+        if self
+            .scheduled
+            .get(&key)
+            .is_some_and(|&existing| existing <= deadline)
+        {
+            return;
+        }
+        self.scheduled.insert(key, deadline);
+        self.heap.push(Reverse((deadline, key)));
+    }
+
+    /// Returns the earliest deadline in the queue, or `None` if the queue is empty.
+    pub(crate) fn next_deadline(&mut self) -> Option<Instant> {
+        while let Some(Reverse((deadline, key))) = self.heap.peek() {
+            if self.scheduled.get(key) == Some(deadline) {
+                return Some(*deadline);
+            }
+            self.heap.pop();
+        }
+        None
+    }
+
+    /// Removes and returns a key whose deadline is at or before `now`, or `None` if no deadlines
+    /// have expired.
+    pub(crate) fn pop_expired(&mut self, now: Instant) -> Option<K> {
+        if self.next_deadline()? > now {
+            return None;
+        }
+        let Reverse((_, key)) = self.heap.pop()?;
+        self.scheduled.remove(&key);
+        Some(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn expires_in_order() {
+        let now = Instant::now();
+        let mut timers = TimerQueue::new();
+        timers.schedule(1, now + Duration::from_millis(20));
+        timers.schedule(2, now + Duration::from_millis(10));
+        timers.schedule(3, now + Duration::from_millis(30));
+
+        assert_eq!(
+            timers.next_deadline(),
+            Some(now + Duration::from_millis(10))
+        );
+        assert_eq!(timers.pop_expired(now), None);
+        let later = now + Duration::from_millis(20);
+        assert_eq!(timers.pop_expired(later), Some(2));
+        assert_eq!(timers.pop_expired(later), Some(1));
+        assert_eq!(timers.pop_expired(later), None);
+        assert_eq!(
+            timers.next_deadline(),
+            Some(now + Duration::from_millis(30))
+        );
+    }
+
+    #[test]
+    fn earliest_deadline_wins() {
+        let now = Instant::now();
+        let mut timers = TimerQueue::new();
+        timers.schedule(1, now + Duration::from_millis(20));
+        timers.schedule(1, now + Duration::from_millis(30));
+        assert_eq!(
+            timers.next_deadline(),
+            Some(now + Duration::from_millis(20))
+        );
+
+        // an earlier deadline replaces the old one, which is skipped from then on.
+        timers.schedule(1, now + Duration::from_millis(10));
+        assert_eq!(timers.pop_expired(now + Duration::from_secs(1)), Some(1));
+        assert_eq!(timers.pop_expired(now + Duration::from_secs(1)), None);
+        assert_eq!(timers.next_deadline(), None);
+    }
+}
@@ -41,7 +41,7 @@ impl fmt::Display for Snapshot {
 impl From<Vec<&str>> for Snapshot {
     fn from(iter: Vec<&str>) -> Self {
         // get fields.
-        let if_name = iter.get(0).expect("Missing interface name");
+        let if_name = iter.first().expect("Missing interface name");
         let rx_bytes_str = iter.get(1).expect("Missing rx_bytes");
         let tx_bytes_str = iter.get(9).expect("Missing tx_bytes");
 
@@ -3,6 +3,12 @@ use std::process::Command;
 
 pub struct SnapshotTaker;
 
+impl Default for SnapshotTaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SnapshotTaker {
     pub fn new() -> Self {
         Self
@@ -1,5 +1,6 @@
 use common::logger::Logger;
 use protocol::lrdp_socket::LrdpSocket;
+use protocol::lrdp_stats::LrdpStats;
 use throughput_recorder::snapshot::Snapshot;
 use throughput_recorder::snapshot_taker::SnapshotTaker;
 
@@ -17,36 +18,39 @@ impl LrdpConsumer {
         }
     }
 
-    pub fn consume(&mut self) -> () {
+    pub fn consume(&mut self) {
         let mut socket = LrdpSocket::bind("0.0.0.0:6860").expect("Cannot create LRDP socket");
         let mut recv_sum = 0;
         let mut packet_count = 0;
         // log initial snapshot.
-        self.logger
-            .log(format!("0,{},{}", recv_sum, self.snapshot().to_string()));
+        self.logger.log(format!(
+            "0,{},{},{}",
+            recv_sum,
+            self.snapshot(),
+            LrdpStats::default()
+        ));
 
-        loop {
-            let (bytes_received, from_addr) = match socket.recv_from() {
-                Ok((a, b)) => (a, b),
-                _ => break,
-            };
+        while let Ok((bytes_received, from_addr)) = socket.recv_from() {
             if bytes_received.is_empty() {
                 self.logger
-                    .log_msg(format!("Received 0 bytes. Shutting down socket."));
+                    .log_msg("Received 0 bytes. Shutting down socket.");
                 break;
             } else {
                 self.logger.log_msg(format!(
                     "Received {} bytes from {}",
                     bytes_received.len(),
-                    from_addr.to_string()
+                    from_addr
                 ));
                 recv_sum += bytes_received.len();
                 packet_count += 1;
+                // the protocol statistics for the producer follow the snapshot.
+                let stats = socket.peer_stats(from_addr).unwrap_or_default();
                 self.logger.log(format!(
-                    "{},{},{}",
+                    "{},{},{},{}",
                     packet_count,
                     recv_sum,
-                    self.snapshot().to_string()
+                    self.snapshot(),
+                    stats
                 ));
             }
         }
@@ -2,8 +2,6 @@ mod lrdp_consumer;
 mod tcp_consumer;
 mod udp_consumer;
 
-use pretty_env_logger;
-
 use crate::lrdp_consumer::LrdpConsumer;
 use crate::tcp_consumer::TcpConsumer;
 use crate::udp_consumer::UdpConsumer;
@@ -18,21 +18,21 @@ impl TcpConsumer {
         }
     }
 
-    pub fn consume(&mut self) -> () {
+    pub fn consume(&mut self) {
         let listener = TcpListener::bind("0.0.0.0:6860").expect("Cannot create TCP listener");
         let mut recv_sum = 0;
         let mut packet_count = 0;
         // log initial snapshot.
         self.logger
-            .log(&format!("0,{},{}", recv_sum, self.snapshot().to_string()));
+            .log(format!("0,{},{}", recv_sum, self.snapshot()));
 
         let (mut socket, from_addr) = listener.accept().expect("Cannot establish connection");
         self.logger.log_msg(format!(
             "Received connection from {}",
-            from_addr.to_string()
+            from_addr
         ));
 
-        let mut buf = [0u8; std::u16::MAX as usize];
+        let mut buf = [0u8; u16::MAX as usize];
         loop {
             let bytes_received = socket.read(&mut buf).expect("Cannot read from socket");
             if bytes_received == 0 {
@@ -47,11 +47,11 @@ impl TcpConsumer {
                     .log_msg(format!("Received {} bytes", bytes_received));
                 recv_sum += bytes_received;
                 packet_count += 1;
-                self.logger.log(&format!(
+                self.logger.log(format!(
                     "{},{},{}",
                     packet_count,
                     recv_sum,
-                    self.snapshot().to_string()
+                    self.snapshot()
                 ));
             }
         }
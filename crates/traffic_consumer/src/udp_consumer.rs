@@ -17,27 +17,27 @@ impl UdpConsumer {
         }
     }
 
-    pub fn consume(&mut self) -> () {
+    pub fn consume(&mut self) {
         let socket = UdpSocket::bind("0.0.0.0:6860").expect("Cannot create UDP socket");
-        let mut buf = [0u8; std::u16::MAX as usize];
+        let mut buf = [0u8; u16::MAX as usize];
         let mut recv_sum = 0;
         let mut packet_count = 0;
         // log initial snapshot.
         self.logger
-            .log(format!("0,{},{}", recv_sum, self.snapshot().to_string()));
+            .log(format!("0,{},{}", recv_sum, self.snapshot()));
 
         loop {
             let (bytes_received, from_addr) =
                 socket.recv_from(&mut buf).expect("Cannot read from socket");
             if bytes_received == 0 {
                 self.logger
-                    .log_msg(format!("Received 0 bytes. Shutting down socket."));
+                    .log_msg("Received 0 bytes. Shutting down socket.");
                 break;
             } else {
                 self.logger.log_msg(format!(
                     "Received {} bytes from {}",
                     bytes_received,
-                    from_addr.to_string()
+                    from_addr
                 ));
                 recv_sum += bytes_received;
                 packet_count += 1;
@@ -45,7 +45,7 @@ impl UdpConsumer {
                     "{},{},{}",
                     packet_count,
                     recv_sum,
-                    self.snapshot().to_string()
+                    self.snapshot()
                 ));
             }
         }
@@ -1,6 +1,7 @@
 use crate::payload::create_payload;
 use crate::producer::{Producer, ProducerRun};
 use protocol::lrdp_socket::LrdpSocket;
+use protocol::lrdp_stats::LrdpStats;
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::thread;
 use std::time::Duration;
@@ -25,9 +26,14 @@ impl Producer for LrdpProducer {
     fn run(&self, runner: &mut ProducerRun) {
         // log an initial snapshot.
         let snapshot = runner.snapshot().to_string();
-        runner.logger.log(format!("0,0,{}", snapshot));
+        runner
+            .logger
+            .log(format!("0,0,{},{}", snapshot, LrdpStats::default()));
 
         let mut socket = LrdpSocket::bind("0.0.0.0:0").expect("Cannot create LRDP socket");
+        socket
+            .connect(self.destination)
+            .expect("Cannot connect to LRDP consumer");
         let delay_ms: u64 = (1000.0 / runner.opts.rate) as u64;
         let mut sent_sum = 0;
         for i in 0..runner.opts.count {
@@ -35,22 +41,33 @@ impl Producer for LrdpProducer {
                 .logger
                 .log_msg(format!("Sending packet {} of {}", i + 1, runner.opts.count));
             let payload = create_payload(runner.opts.payload_size as usize);
-            socket
-                .send_to(self.destination, payload.as_slice())
-                .unwrap();
+            socket.send(payload.as_slice()).unwrap();
             sent_sum += runner.opts.payload_size;
-            // log the total packets sent, total bytes sent, and the current snapshot.
+            // log the total packets sent, total bytes sent, the current snapshot, and the protocol
+            // statistics for the consumer.
             let snapshot = runner.snapshot().to_string();
+            let stats = socket.peer_stats(self.destination).unwrap_or_default();
             runner
                 .logger
-                .log(format!("{},{},{}", i + 1, sent_sum, snapshot));
+                .log(format!("{},{},{},{}", i + 1, sent_sum, snapshot, stats));
+            // log the current round trip time estimate for the consumer.
+            if let Some(rtt) = socket.peer_rtt(self.destination) {
+                runner.logger.log_msg(format!(
+                    "SRTT {:?}, RTTVAR {:?}, RTO {:?}",
+                    rtt.srtt(),
+                    rtt.rttvar(),
+                    rtt.rto()
+                ));
+            }
             thread::sleep(Duration::from_millis(delay_ms));
         }
-        // send a "closing" packet.
-        socket
-            .send_to(self.destination, &[])
-            .unwrap();
-
-        socket.stop();
+        // close the connection once everything has been delivered, and stop the socket.
+        let undelivered = socket.close_with_timeout(Duration::from_secs(10));
+        if !undelivered.is_empty() {
+            runner.logger.log_msg(format!(
+                "Gave up on {} packets which were never delivered",
+                undelivered.len()
+            ));
+        }
     }
 }
@@ -4,8 +4,6 @@ mod lrdp_producer;
 mod tcp_producer;
 mod udp_producer;
 
-use pretty_env_logger;
-
 use crate::producer::{Producer, ProducerOptions, ProducerRun};
 use crate::lrdp_producer::LrdpProducer;
 use crate::tcp_producer::TcpProducer;
@@ -49,7 +49,7 @@ impl ProducerRun {
         }
     }
 
-    pub fn run(&mut self, producer: Box<dyn Producer>) -> () {
+    pub fn run(&mut self, producer: Box<dyn Producer>) {
         producer.run(self);
     }
 
@@ -30,12 +30,12 @@ impl Producer for TcpProducer {
         let mut socket = TcpStream::connect(self.destination).expect("Cannot create TCP socket");
         let delay_ms: u64 = (1000.0 / runner.opts.rate) as u64;
         let mut sent_sum = 0;
-        let mut payload = create_payload(runner.opts.payload_size as usize);
+        let payload = create_payload(runner.opts.payload_size as usize);
         for i in 0..runner.opts.count {
             runner
                 .logger
                 .log_msg(format!("Sending packet {} of {}", i + 1, runner.opts.count));
-            socket.write_all(&mut payload).expect("Cannot send data");
+            socket.write_all(&payload).expect("Cannot send data");
             socket.flush().expect("Cannot flush stream");
             sent_sum += runner.opts.payload_size;
             // log the total packets sent, total bytes sent, and the current snapshot.
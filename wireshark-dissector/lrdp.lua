@@ -3,6 +3,17 @@ local f_data_flag = ProtoField.new("Data flag", "lrdp.data_flag", ftypes.BOOLEAN
 local f_ack_flag = ProtoField.new("Ack flag", "lrdp.ack_flag", ftypes.BOOLEAN, nil, base.DEC, 64)
 local f_seq_num = ProtoField.new("Sequence number", "lrdp.seq_num", ftypes.UINT8, nil, base.DEC, 56)
 local f_ack_num = ProtoField.new("Acknowledgement number", "lrdp.ack_num", ftypes.UINT8, nil, base.DEC, 7)
+local f_more_fragments = ProtoField.new("More fragments", "lrdp.more_fragments", ftypes.BOOLEAN, nil, base.DEC, 1)
+local f_control = ProtoField.new("Control type", "lrdp.control", ftypes.UINT8, {
+  [1] = "SYN",
+  [2] = "SYN-ACK",
+  [3] = "FIN",
+  [4] = "FIN-ACK",
+  [5] = "KEEPALIVE",
+  [6] = "FORWARD"
+}, base.DEC, 63)
+local f_forward = ProtoField.new("Forward to sequence number", "lrdp.forward", ftypes.UINT16)
+local f_sack = ProtoField.new("Selective ack bitmap", "lrdp.sack", ftypes.BYTES)
 local f_data = ProtoField.new("Data", "lrdp.data", ftypes.STRING)
 
 p_lrdp.fields = {
@@ -10,6 +21,10 @@ p_lrdp.fields = {
   f_ack_flag,
   f_seq_num,
   f_ack_num,
+  f_more_fragments,
+  f_control,
+  f_forward,
+  f_sack,
   f_data
 }
 
@@ -25,11 +40,39 @@ function p_lrdp.dissector (buf, pkt, root)
   subtree:add(f_data_flag, buf(0, 1))
   subtree:add(f_ack_flag, buf(0, 1))
 
+  -- if neither flag is set then this is either a control packet or an unreliable packet.
+  if bit.band(buf(0, 1):uint(), 192) == 0 then
+    if bit.band(buf(0, 1):uint(), 63) ~= 0 then
+      subtree:add(f_control, buf(0, 1))
+    end
+    -- a FORWARD carries the sequence number to skip ahead to.
+    if bit.band(buf(0, 1):uint(), 63) == 6 and buf:len() == 3 then
+      subtree:add(f_forward, buf(1, 2))
+      return
+    end
+    if buf:len() > 1 then
+      subtree:add(f_data, buf(1, -1))
+    end
+    return
+  end
+
   -- seq num.
   subtree:add(f_seq_num, buf(0, 1))
 
-  -- ack num.
-  subtree:add(f_ack_num, buf(0, 1))
+  -- ack num. Data packets without an ack use the lowest bit to say that more fragments follow.
+  if bit.band(buf(0, 1):uint(), 64) ~= 0 then
+    subtree:add(f_ack_num, buf(0, 1))
+  else
+    subtree:add(f_more_fragments, buf(0, 1))
+  end
+
+  -- anything after the header of a standalone ack is a selective ack bitmap.
+  if bit.band(buf(0, 1):uint(), 192) == 64 then
+    if buf:len() > 1 then
+      subtree:add(f_sack, buf(1, -1))
+    end
+    return
+  end
 
   -- data.
   subtree:add(f_data, buf(1, -1))